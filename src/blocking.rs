@@ -0,0 +1,55 @@
+use log::info;
+use tokio::{runtime::{Builder, Runtime}, sync::mpsc::{error::{SendError, TrySendError}, Sender}};
+
+use crate::{error::QuickStreamError, upsert::{ShutdownReport, StreamHandle, Upsert, UpsertQuickStream}};
+
+/**
+ Runs an `UpsertQuickStream` on a runtime of its own, for callers without an async runtime.
+ * Every push blocks the calling thread until the main channel has room, which makes it slower than pushing from async code.
+ * Must not be used from within an async context, as blocking a runtime thread panics.
+ */
+pub struct BlockingQuickStream<T> where T: Upsert<T> + Clone + Send + 'static {
+    upsert_quick_stream: UpsertQuickStream,
+    runtime: Runtime,
+    tx: Sender<Vec<T>>,
+    handle: StreamHandle
+}
+
+impl<T> BlockingQuickStream<T> where T: Upsert<T> + Clone + Send + 'static {
+    /// Creates a multi threaded runtime and starts the stream on it
+    pub fn start(upsert_quick_stream: UpsertQuickStream) -> Result<Self, QuickStreamError> {
+        info!("{}: starting blocking upsert quick stream", upsert_quick_stream.name);
+        let runtime = Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let (tx, handle) = runtime.block_on(async { upsert_quick_stream.start::<T>() });
+
+        Ok(Self { upsert_quick_stream, runtime, tx, handle })
+    }
+
+    /// Pushes a payload into the stream, blocking while the main channel is full. Returns the payload if the stream stopped.
+    pub fn push(&self, data: Vec<T>) -> Result<(), SendError<Vec<T>>> {
+        self.tx.blocking_send(data)
+    }
+
+    /**
+     Pushes a payload into the stream without blocking, failing right away with the payload when the main channel is full or the stream stopped.
+     * For producers which must never block, e.g. event loops, to drop the payload, buffer it elsewhere or slow down themselves
+     * Unlike `push` it may be called from within an async context
+     */
+    pub fn try_push(&self, data: Vec<T>) -> Result<(), TrySendError<Vec<T>>> {
+        self.tx.try_send(data)
+    }
+
+    /**
+     Closes the main channel and blocks until every pushed record is written, then shuts the runtime down.
+     * Returns the report of the stream once drained, or its error if it failed.
+     */
+    pub fn shutdown(self) -> Result<ShutdownReport, QuickStreamError> {
+        info!("{}: shutting down blocking upsert quick stream", self.upsert_quick_stream.name);
+        drop(self.tx);
+        let result = self.runtime.block_on(self.handle.completed());
+        info!("{}: blocking upsert quick stream shut down", self.upsert_quick_stream.name);
+        result
+    }
+}
@@ -1,12 +1,14 @@
-use std::process::{ExitCode, Termination};
+use std::{borrow::Borrow, collections::{HashMap, VecDeque}, fs, path::Path, process::{ExitCode, Termination}, sync::{atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering}, Arc, Mutex, RwLock}, time::Duration};
 
-use log::trace;
+use log::{trace, Level};
 use native_tls::Certificate;
 use random_word::Lang;
 use support::QueryHolder;
+use tokio::{runtime::Handle, sync::{mpsc::Sender, Notify, Semaphore}};
+use tokio_postgres::Row;
 use tokio_util::sync::CancellationToken;
 
-use crate::upsert::UpsertQuickStream;
+use crate::{clock::{Clock, TokioClock}, error::QuickStreamError, schema::TableSchema, upsert::{BatchConfirmation, ConfirmationHook, ConflictMode, DispatchEvent, DispatchHook, ExecutionMode, RebalanceEvent, RebalanceHook, SenderSelection, StreamState, TokenBucket, UpsertQuickStream, READ_ONLY_MAX_RETRIES, TOO_MANY_CONNECTIONS_COOLDOWN}};
 
 pub mod support;
 
@@ -18,15 +20,57 @@ pub struct QuickStreamBuilder {
     single_digits: Option<usize>,
     tens: Option<usize>,
     hundreds: Option<usize>,
+    fifties: Option<usize>,
     db_config: Option<tokio_postgres::Config>,
     tls: Option<Certificate>,
     queries: Option<QueryHolder>,
+    do_nothing_queries: Option<QueryHolder>,
+    dual_write_queries: Option<QueryHolder>,
+    variant_queries: HashMap<String, QueryHolder>,
+    returning: Option<Sender<Vec<Row>>>,
+    conflict_do_nothing: bool,
     max_records_per_cycle_batch: Option<usize>, //a batch = introduced_lag_cycles
     introduced_lag_cycles: Option<usize>,
     introduced_lag_in_millies: Option<u64>,
     connection_creation_threshold: Option<f64>,
     name: Option<String>,
-    print_connection_configuration: bool
+    print_connection_configuration: bool,
+    status_log_level: Option<Level>,
+    slow_batch_threshold: Option<Duration>,
+    read_only_reconnect_delay: Option<Duration>,
+    read_only_max_retries: Option<usize>,
+    commit_batch_size: Option<usize>,
+    stalled_ingestor_threshold: Option<Duration>,
+    recreate_stalled_ingestors: bool,
+    dry_run: bool,
+    max_in_flight_records_per_connection: Option<usize>,
+    max_in_flight_records: Option<usize>,
+    session_variable: Option<String>,
+    connect_timeout: Option<Duration>,
+    max_connection_lifetime: Option<Duration>,
+    max_total_rows: Option<u64>,
+    rebalance_hook: Option<RebalanceHook>,
+    dispatch_hook: Option<DispatchHook>,
+    confirmation_hook: Option<ConfirmationHook>,
+    scale_up_hysteresis: Option<(f64, Duration)>,
+    connection_removal_threshold: Option<f64>,
+    connection_removal_cooldown: Option<Duration>,
+    max_connection_creation_rate: Option<(u32, Duration)>,
+    too_many_connections_cooldown: Option<Duration>,
+    strict_readiness: bool,
+    skip_connectivity_test: bool,
+    adaptive_lag_cycles: bool,
+    deterministic: bool,
+    clock: Option<Arc<dyn Clock>>,
+    flush_below_records: Option<usize>,
+    watermark_query: Option<(String, Duration)>,
+    runtime_handle: Option<Handle>,
+    max_payload_records: Option<usize>,
+    verify_schema: bool,
+    expected_columns: Vec<TableSchema>,
+    strict_fifo: bool,
+    execution_mode: Option<ExecutionMode>,
+    sender_selection: Option<SenderSelection>
 }
 
 impl Default for QuickStreamBuilder {
@@ -38,15 +82,57 @@ impl Default for QuickStreamBuilder {
             single_digits: None,
             tens: None,
             hundreds: None,
+            fifties: None,
             db_config: None,
             tls: None,
             queries: None,
+            do_nothing_queries: None,
+            dual_write_queries: None,
+            variant_queries: HashMap::new(),
+            returning: None,
+            conflict_do_nothing: false,
             max_records_per_cycle_batch: None,
             introduced_lag_cycles: None,
             introduced_lag_in_millies: None,
             connection_creation_threshold: None,
             name: Some(format!("{}_{}", random_word::gen(Lang::En), random_word::gen(Lang::En))),
-            print_connection_configuration: false
+            print_connection_configuration: false,
+            status_log_level: None,
+            slow_batch_threshold: None,
+            read_only_reconnect_delay: None,
+            read_only_max_retries: None,
+            commit_batch_size: None,
+            stalled_ingestor_threshold: None,
+            recreate_stalled_ingestors: false,
+            dry_run: false,
+            max_in_flight_records_per_connection: None,
+            max_in_flight_records: None,
+            session_variable: None,
+            connect_timeout: None,
+            max_connection_lifetime: None,
+            max_total_rows: None,
+            rebalance_hook: None,
+            dispatch_hook: None,
+            confirmation_hook: None,
+            scale_up_hysteresis: None,
+            connection_removal_threshold: None,
+            connection_removal_cooldown: None,
+            max_connection_creation_rate: None,
+            too_many_connections_cooldown: None,
+            strict_readiness: false,
+            skip_connectivity_test: false,
+            adaptive_lag_cycles: false,
+            deterministic: false,
+            clock: None,
+            flush_below_records: None,
+            watermark_query: None,
+            runtime_handle: None,
+            max_payload_records: None,
+            verify_schema: false,
+            expected_columns: vec![],
+            strict_fifo: false,
+            execution_mode: None,
+            sender_selection: None
         }
     }
 }
@@ -63,6 +149,10 @@ impl QuickStreamBuilder {
         self
     }
 
+    /**
+     Maximum amount of connections the stream opens across every tier.
+     * Must be at least the initial senders of the tiers, 9 * `single_digits` + `tens` + `fifties` + `hundreds`, otherwise `build_update` panics
+     */
     pub fn max_connection_count(&mut self, max_con_count: usize) -> &mut Self {
         self.max_con_count = Some(max_con_count);
         self
@@ -88,8 +178,24 @@ impl QuickStreamBuilder {
         self
     }
 
-    pub fn db_config(&mut self, db_config: tokio_postgres::Config) -> &mut Self {
-        self.db_config = Some(db_config);
+    /**
+     Adds a tier of 50 records between the tens and the hundreds, with the given amount of initial senders.
+     Mid sized batches then take fewer round trips, e.g. 90 records are written in 5 batches (50 + 4 * 10) instead of 9.
+     * Requires the query for 50 in `queries`, and in `do_nothing_queries`, `dual_write_queries` and `variant_queries` if set
+     * ***Default behaviour is to split the records below a hundred into batches of ten and single digits***
+     */
+    pub fn fifties(&mut self, fifties: usize) -> &mut Self {
+        self.fifties = Some(fifties);
+        self
+    }
+
+    /**
+     Sets the database connection parameters. Accepts an owned `Config`, a `&Config` or an `Arc<Config>`,
+     so the same parameters can be shared between several streams to the same database.
+     * The config is cloned into the stream, tls is configured separately for every stream through `tls`
+     */
+    pub fn db_config<C: Borrow<tokio_postgres::Config>>(&mut self, db_config: C) -> &mut Self {
+        self.db_config = Some(db_config.borrow().clone());
         self
     }
 
@@ -98,16 +204,125 @@ impl QuickStreamBuilder {
         self
     }
 
+    /**
+     Parses the given PEM encoded root certificate and enables tls with it.
+     * Fails with `QuickStreamError::Tls` if the certificate can not be parsed
+     */
+    pub fn tls_root_cert_pem(&mut self, pem: &[u8]) -> Result<&mut Self, QuickStreamError> {
+        self.tls = Some(Certificate::from_pem(pem)?);
+        Ok(self)
+    }
+
+    /**
+     Parses the given DER encoded root certificate and enables tls with it.
+     * Fails with `QuickStreamError::Tls` if the certificate can not be parsed
+     */
+    pub fn tls_root_cert_der(&mut self, der: &[u8]) -> Result<&mut Self, QuickStreamError> {
+        self.tls = Some(Certificate::from_der(der)?);
+        Ok(self)
+    }
+
+    /**
+     Reads the root certificate from the given file and enables tls with it. The format is detected by trying PEM first and then DER.
+     * Fails with `QuickStreamError::Io` if the file can not be read and with `QuickStreamError::Tls` if it is neither a PEM nor a DER certificate
+     */
+    pub fn tls_root_cert_file<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, QuickStreamError> {
+        let bytes = fs::read(path)?;
+        let certificate = match Certificate::from_pem(&bytes) {
+            Ok(certificate) => certificate,
+            Err(_) => {
+                trace!("certificate is not PEM encoded, trying DER");
+                Certificate::from_der(&bytes)?
+            },
+        };
+        self.tls = Some(certificate);
+        Ok(self)
+    }
+
     pub fn queries(&mut self, queries: QueryHolder) -> &mut Self {
         self.queries = Some(queries);
         self
     }
 
+    /**
+     Queries executed instead of `queries` while the conflict mode is `ConflictMode::DoNothing`, usually the same inserts
+     with `ON CONFLICT DO NOTHING`. Both are prepared upfront, so `UpsertQuickStream::set_conflict_mode` switches between
+     them without restarting, e.g. skipping existing rows during a backfill and overwriting them once streaming.
+     * `build_update` panics when combined with `strict_fifo`, which always executes `queries`
+     * ***Default behaviour is to always execute `queries`***
+     */
+    pub fn do_nothing_queries(&mut self, do_nothing_queries: QueryHolder) -> &mut Self {
+        self.do_nothing_queries = Some(do_nothing_queries);
+        self
+    }
+
+    /**
+     Queries executed for every batch after `queries`, binding the same records, in the same transaction. For online schema migrations,
+     writing both the old and the new shape of a table until readers moved to the new one.
+     * A batch is written by both queries or by neither, its rows are those of `queries`
+     * Every batch costs two statements and a transaction, roughly halving the throughput per connection, and is cloned once
+     * Executed in `ConflictMode::DoNothing` too, and with `ExecutionMode::SingleRowPipelined` its query of tier 1 is used
+     * `build_update` panics when combined with `strict_fifo`, which writes each batch with `queries` only
+     * ***Default behaviour is to write each batch with `queries` only***
+     */
+    pub fn dual_write_queries(&mut self, dual_write_queries: QueryHolder) -> &mut Self {
+        self.dual_write_queries = Some(dual_write_queries);
+        self
+    }
+
+    /**
+     Queries executed instead of `queries` for the records whose `Upsert::statement_variant` returns the given variant,
+     e.g. a soft delete for records flagged as deleted next to the upsert of the others. Call it once per variant.
+     * Records are grouped by their variant before they are split into batches, so every batch holds a single variant
+     * Executed in `ConflictMode::DoNothing` too, and with `ExecutionMode::SingleRowPipelined` its query of tier 1 is used
+     * A batch whose variant has no queries fails with `MissingVariant`, stopping its data ingestor like a database error
+     * `build_update` panics when combined with `strict_fifo`, which writes every record with `queries`
+     * ***Default behaviour is to write every record with `queries`***
+     */
+    pub fn variant_queries(&mut self, variant: &str, variant_queries: QueryHolder) -> &mut Self {
+        self.variant_queries.insert(variant.to_owned(), variant_queries);
+        self
+    }
+
+    /**
+     Forwards the rows returned by the `RETURNING` clause of `queries` to the given channel, one message per written batch,
+     e.g. to feed the values as written to a change log downstream. Batches are written with `Upsert::upsert_returning` instead of `Upsert::upsert`,
+     which the records have to implement along with `Upsert::RETURNING`, `run` fails with `ReturningUnsupported` otherwise.
+     * Batches returning no rows, e.g. skipped by `ON CONFLICT DO NOTHING`, are not forwarded
+     * A full channel holds back the data ingestors, a closed one drops the returned rows with a warning
+     * The rows of `dual_write_queries` are not forwarded
+     * `build_update` panics when combined with `strict_fifo`, which writes the batches with `Upsert::upsert`
+     * ***Default behaviour is to execute the queries without collecting rows***
+     */
+    pub fn returning(&mut self, returning: Sender<Vec<Row>>) -> &mut Self {
+        self.returning = Some(returning);
+        self
+    }
+
+    /**
+     Starts the stream in `ConflictMode::DoNothing`, requires `do_nothing_queries`.
+     * ***Default behaviour is to start in `ConflictMode::DoUpdate`***
+     */
+    pub fn conflict_do_nothing(&mut self) -> &mut Self {
+        self.conflict_do_nothing = true;
+        self
+    }
+
     pub fn max_records_per_cycle_batch(&mut self, max_records_per_cycle_batch: usize) -> &mut Self {
         self.max_records_per_cycle_batch = Some(max_records_per_cycle_batch);
         self
     }
 
+    /**
+     Payloads with more records than this are not rejected but ingested in chunks of this size, after removing duplicates across the whole payload.
+     This bounds the work of a single oversized payload. The last, partial chunk is processed like a regular payload.
+     * ***Default behaviour is to ingest a payload as a whole regardless of its size***
+     */
+    pub fn max_payload_records(&mut self, max_payload_records: usize) -> &mut Self {
+        self.max_payload_records = Some(max_payload_records);
+        self
+    }
+
     pub fn introduced_lag_cycles(&mut self, introduced_lag_cycles: usize) -> &mut Self {
         self.introduced_lag_cycles = Some(introduced_lag_cycles);
         self
@@ -118,11 +333,161 @@ impl QuickStreamBuilder {
         self
     }
 
+    /**
+     Reduces the `introduced_lag_cycles` as the channels of the senders fill up, down to no lag cycles when they are all full.
+     A loaded stream flushes small payloads sooner to keep up, while an idle stream waits the full lag cycles to batch better.
+     * ***Default behaviour is to always use the `introduced_lag_cycles`***
+     */
+    pub fn adaptive_lag_cycles(&mut self) -> &mut Self {
+        self.adaptive_lag_cycles = true;
+        self
+    }
+
+    /**
+     Skips the remaining lag cycles as soon as a lag cycle receives nothing while fewer than the given records are buffered.
+     Lowers the latency of low volume streams, whose few records would otherwise wait every lag cycle for a batch which never fills up.
+     * A payload arriving within the first lag cycle is still batched with the buffered records
+     * ***Default behaviour is to wait the `introduced_lag_cycles` whatever the amount of buffered records***
+     */
+    pub fn flush_below_records(&mut self, flush_below_records: usize) -> &mut Self {
+        self.flush_below_records = Some(flush_below_records);
+        self
+    }
+
     pub fn connection_creation_threshold(&mut self, connection_creation_threshold: f64) -> &mut Self {
         self.connection_creation_threshold = Some(connection_creation_threshold);
         self
     }
 
+    /**
+     Lowers the `connection_creation_threshold` by the given percentage points for the given window after a connection is created,
+     so the capacity has to drop further before the next connection is created. This damps bursts creating several connections back to back.
+     * e.g. a threshold of 25% with a reduction of 15 creates the next connection at 10% capacity within the window
+     * ***Default behaviour is to always use the `connection_creation_threshold`***
+     */
+    pub fn scale_up_hysteresis(&mut self, threshold_reduction: f64, window: Duration) -> &mut Self {
+        self.scale_up_hysteresis = Some((threshold_reduction, window));
+        self
+    }
+
+    /**
+     Connections whose capacity is at or above this percentage are considered idle and may be removed down to the initial amount of their tier.
+     Keep it well above the `connection_creation_threshold` so a connection is not removed right after it was created.
+     * ***Default behaviour is to only remove connections with an empty channel, i.e. 100%***
+     */
+    pub fn connection_removal_threshold(&mut self, connection_removal_threshold: f64) -> &mut Self {
+        self.connection_removal_threshold = Some(connection_removal_threshold);
+        self
+    }
+
+    /**
+     `run` waits until every initial connection is established and prepared its statement before receiving data,
+     and fails with the first error instead. This catches a database node with a stale schema at startup.
+     * ***Default behaviour is to log the error of the failing data ingestor and continue with the others***
+     */
+    pub fn strict_readiness(&mut self) -> &mut Self {
+        self.strict_readiness = true;
+        self
+    }
+
+    /**
+     `run` starts the data ingestors right away instead of opening and dropping a connection to test the database first.
+     Connection errors are then only logged by the failing data ingestors, combine with `strict_readiness` to still fail `run` on them.
+     * ***Default behaviour is to test the database connection before starting***
+     */
+    pub fn skip_connectivity_test(&mut self) -> &mut Self {
+        self.skip_connectivity_test = true;
+        self
+    }
+
+    /**
+     Writes the records on a single connection, batch after batch, in exactly the order they were received from the main channel.
+     For streams whose consumers need the global order, at the cost of throughput, as nothing is written in parallel.
+     * The tiers, queries and `max_total_rows` are still used, but no senders are created, so the lag cycles and every scaling option are ignored
+     * Duplicates of a payload are reduced to the last record of every primary key, in place of the record with the latest `modified_date`
+     * `build_update` panics when combined with `do_nothing_queries`, `dual_write_queries`, `variant_queries` or `returning`, which are not executed
+     * ***Default behaviour is to write the batches in parallel over the senders of every tier***
+     */
+    pub fn strict_fifo(&mut self) -> &mut Self {
+        self.strict_fifo = true;
+        self
+    }
+
+    /**
+     Sets how the data ingestors execute a batch, see `ExecutionMode`. With `ExecutionMode::SingleRowPipelined` only the query of tier 1
+     is prepared and executed, the queries of the other tiers are not used. The tiers still decide how records are batched and scaled.
+     * Not used with `strict_fifo`, which always executes the multi row statements
+     * ***Default behaviour is `ExecutionMode::MultiRow`***
+     */
+    pub fn execution_mode(&mut self, execution_mode: ExecutionMode) -> &mut Self {
+        self.execution_mode = Some(execution_mode);
+        self
+    }
+
+    /**
+     Sets how a batch picks its sender when the tier does not scale up, see `SenderSelection`. With `SenderSelection::WeightedRandom`
+     every connection keeps receiving batches, so they stay warm and idle senders are only removed once the load drops for the whole tier.
+     * Scaling up is still decided by the sender with the highest capacity
+     * ***Default behaviour is `SenderSelection::HighestCapacity`***
+     */
+    pub fn sender_selection(&mut self, sender_selection: SenderSelection) -> &mut Self {
+        self.sender_selection = Some(sender_selection);
+        self
+    }
+
+    /**
+     Before streaming, checks the columns declared with `expected_columns` against `information_schema.columns`.
+     `run` fails with every missing table, missing column and type mismatch, instead of the data ingestors failing on execute later.
+     * ***Default behaviour is to not verify the schema***
+     */
+    pub fn verify_schema(&mut self) -> &mut Self {
+        self.verify_schema = true;
+        self
+    }
+
+    /**
+     Declares the columns a table is expected to have for `verify_schema`, can be called once per table.
+     The table can be qualified with its schema, e.g. `public.orders`, otherwise it is looked up in the current schema.
+     Types are compared with either the `data_type` (`bigint`) or the `udt_name` (`int8`) of the column, ignoring case.
+     * Columns of the table which are not declared are not checked
+     */
+    pub fn expected_columns(&mut self, table: String, columns: Vec<(String, String)>) -> &mut Self {
+        self.expected_columns.push(TableSchema { table, columns });
+        self
+    }
+
+    /**
+     Connections created within the given duration are never removed, even if they are idle.
+     * ***Default behaviour is to remove idle connections regardless of their age***
+     */
+    pub fn connection_removal_cooldown(&mut self, connection_removal_cooldown: Duration) -> &mut Self {
+        self.connection_removal_cooldown = Some(connection_removal_cooldown);
+        self
+    }
+
+    /**
+     Creates at most the given amount of connections to scale up within the given duration, across all tiers, smoothing the
+     connection pressure on the database when several tiers scale up during a burst. Up to `connections` can be created at once,
+     after which the allowance refills evenly over `per`. While it is used up, batches wait for the existing senders instead.
+     * ***Default behaviour is to create connections as fast as the load requires, up to `max_connection_count`***
+     */
+    pub fn max_connection_creation_rate(&mut self, connections: u32, per: Duration) -> &mut Self {
+        self.max_connection_creation_rate = Some((connections, per));
+        self
+    }
+
+    /**
+     Pauses scaling up for the given duration once the database refused a connection as it has too many clients,
+     the stream continues with its existing senders meanwhile. The allowance of `max_connection_creation_rate` is used up as well,
+     so connections are created at the configured rate again after the pause.
+     * Senders respawned to restore the initial amount of a tier wait for the pause as well, a tier left without senders still gets one
+     * ***Default behaviour is to pause scaling up for 30 seconds***
+     */
+    pub fn too_many_connections_cooldown(&mut self, too_many_connections_cooldown: Duration) -> &mut Self {
+        self.too_many_connections_cooldown = Some(too_many_connections_cooldown);
+        self
+    }
+
     pub fn name(&mut self, name: String) -> &mut Self {
         self.name = Some(name);
         self
@@ -137,33 +502,437 @@ impl QuickStreamBuilder {
         self
     }
 
+    /**
+     Level of the status logs repeated while streaming, the connections configuration, the capacity of the sender of every batch
+     and the connection count whenever a connection is created. Lower it to keep them out of the logs of the application at info level,
+     without changing its logger. Every log of the crate uses the target of its module, e.g. `quick_stream::upsert`, for filtering them further.
+     * ***Default behaviour is to log the status at info level***
+     */
+    pub fn status_log_level(&mut self, status_log_level: Level) -> &mut Self {
+        self.status_log_level = Some(status_log_level);
+        self
+    }
+
+    /**
+     Logs a warning for every batch whose upsert takes longer than the given threshold and counts it,
+     see `UpsertQuickStream::slow_batch_count`.
+     * ***Default behaviour is to not track slow batches***
+     */
+    pub fn slow_batch_threshold(&mut self, slow_batch_threshold: Duration) -> &mut Self {
+        self.slow_batch_threshold = Some(slow_batch_threshold);
+        self
+    }
+
+    /**
+     Writes up to the given amount of batches queued to a data ingestor in one transaction, committing once for all of them
+     instead of once per batch, which saves the commit and its flush to disk of every other batch under high volume.
+     * Only batches already waiting in the channel of the data ingestor are grouped, it never waits for more batches before committing
+     * When one batch of the group fails, the whole group is rolled back and the data ingestor stops with the error,
+       like on a failed single batch. A group failing as the database is read only is not retried with `read_only_reconnect_delay`
+     * Counters, the watermark and ordered confirmations only take the batches of a group into account once it is committed
+     * Values of 1 or less commit every batch on its own. Not used with `strict_fifo`
+     * ***Default behaviour is to commit every batch on its own***
+     */
+    pub fn commit_batch_size(&mut self, commit_batch_size: usize) -> &mut Self {
+        self.commit_batch_size = Some(commit_batch_size);
+        self
+    }
+
+    /**
+     Reconnects a data ingestor whose batch failed as the database is read only, e.g. a primary demoted during a failover,
+     and retries the batch on the new connection after the given delay, up to `read_only_max_retries` times.
+     * Set `target_session_attrs` of the `db_config` to `ReadWrite` with the hosts of every node, so reconnecting picks the new primary
+     * Batches are cloned before they are written while retries are left, to have them at hand for the retry
+     * Cancellation stops waiting for the retry, the batch fails with the read only error like once the retries are used up
+     * ***Default behaviour is to fail the data ingestor like on any other database error***
+     */
+    pub fn read_only_reconnect_delay(&mut self, read_only_reconnect_delay: Duration) -> &mut Self {
+        self.read_only_reconnect_delay = Some(read_only_reconnect_delay);
+        self
+    }
+
+    /**
+     Retries of a batch failing as the database is read only, before the data ingestor fails with the error. Requires `read_only_reconnect_delay`.
+     * ***Default behaviour is to retry up to 10 times***
+     */
+    pub fn read_only_max_retries(&mut self, read_only_max_retries: usize) -> &mut Self {
+        self.read_only_max_retries = Some(read_only_max_retries);
+        self
+    }
+
+    /**
+     Watches for data ingestors which have batches in flight but wrote none for longer than the given threshold, e.g. an upsert hanging
+     on a lock or a lost network without a statement timeout. Every stall is logged and counted once, see `UpsertQuickStream::stalled_ingestor_count`.
+     * Checked whenever the senders are rebalanced, after every payload received from the main channel
+     * The threshold has to be above the slowest expected batch, including the time batches queue in the channel of a data ingestor
+     * ***Default behaviour is to not watch for stalled data ingestors***
+     */
+    pub fn stalled_ingestor_threshold(&mut self, stalled_ingestor_threshold: Duration) -> &mut Self {
+        self.stalled_ingestor_threshold = Some(stalled_ingestor_threshold);
+        self
+    }
+
+    /**
+     Aborts stalled data ingestors and removes their senders, closing their connections. Requires `stalled_ingestor_threshold`.
+     Tiers left below their initial senders get new ones, the others scale up again with the load.
+     * The batch being written and the batches queued to a stalled data ingestor are not written
+     * ***Default behaviour is to only log and count stalled data ingestors***
+     */
+    pub fn recreate_stalled_ingestors(&mut self) -> &mut Self {
+        self.recreate_stalled_ingestors = true;
+        self
+    }
+
+    /**
+     Makes the decisions of the stream reproducible, for tests asserting how batches are dispatched.
+     * `SenderSelection::WeightedRandom` draws from a fixed sequence of numbers instead of random ones, the same for every stream built
+     * `adaptive_lag_cycles` is ignored, the lag cycles no longer depend on how full the channels of the senders are
+     * Time still passes as usual, set a `clock` to control it as well
+     * ***Default behaviour is to pick senders at random and adapt the lag cycles, if enabled***
+     */
+    pub fn deterministic(&mut self) -> &mut Self {
+        self.deterministic = true;
+        self
+    }
+
+    /**
+     Drives every time based decision of the stream with the given clock, e.g. a `ManualClock` advanced by a test, see `Clock`.
+     * ***Default behaviour is to use the clock of the tokio runtime, `TokioClock`***
+     */
+    pub fn clock<C>(&mut self, clock: C) -> &mut Self where C: Clock + 'static {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /**
+     Statements are still prepared against the database, validating the queries, but no batch is written.
+     Every batch is logged with its size, primary keys and the query it would have been executed with.
+     * ***Default behaviour is to upsert the data***
+     */
+    pub fn dry_run(&mut self) -> &mut Self {
+        self.dry_run = true;
+        self
+    }
+
+    /**
+     Caps the records pushed to a single connection which are not written yet. When the chosen connection is at its budget
+     the processor waits for it to write before pushing more, see `UpsertQuickStream::in_flight_records`.
+     * ***Default behaviour is to only be bounded by the channel buffer size***
+     */
+    pub fn max_in_flight_records_per_connection(&mut self, max_in_flight_records_per_connection: usize) -> &mut Self {
+        self.max_in_flight_records_per_connection = Some(max_in_flight_records_per_connection);
+        self
+    }
+
+    /**
+     Caps the records pushed to the data ingestors which are not written yet, across every tier and connection. When the cap is reached
+     the processor waits for batches to be written before pushing more, bounding the memory held by the stream however it scales.
+     * A batch larger than the cap is let through once nothing else is in flight
+     * Batches left in the channel of a data ingestor which stopped on an error are dropped and no longer count against the cap
+     * ***Default behaviour is to only be bounded by the channel buffer size of every connection***
+     */
+    pub fn max_in_flight_records(&mut self, max_in_flight_records: usize) -> &mut Self {
+        self.max_in_flight_records = Some(max_in_flight_records);
+        self
+    }
+
+    /**
+     Sets the given custom session variable to the stream name on every database connection, so triggers and audit logging
+     can attribute writes to the stream with `current_setting('<session_variable>')`.
+     * Custom session variables need a prefix, e.g. `quick_stream.name`
+     * ***Default behaviour is to not set a session variable***
+     */
+    pub fn session_variable(&mut self, session_variable: String) -> &mut Self {
+        self.session_variable = Some(session_variable);
+        self
+    }
+
+    /**
+     Bounds the time taken to establish a database connection, including the connectivity test at startup.
+     Exceeding it fails with a timeout error instead of waiting for the TCP timeout of the platform.
+     * A sender created to scale up is aborted when its data ingestor is not ready within it, or within 30 seconds if that is longer
+     * ***Default behaviour is to wait for the `connect_timeout` of the `tokio_postgres::Config` if any***
+     */
+    pub fn connect_timeout(&mut self, connect_timeout: Duration) -> &mut Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /**
+     Replaces the connection of a data ingestor once it is older than the given duration, e.g. to keep within the connection
+     recycling of a proxy in front of the database. The data ingestor reconnects and prepares its queries again between two batches,
+     the batches waiting in its channel are written with the new connection.
+     * Failing to reconnect keeps the current connection, reconnecting is tried again before the next batch
+     * ***Default behaviour is to keep a connection for as long as it is open***
+     */
+    pub fn max_connection_lifetime(&mut self, max_connection_lifetime: Duration) -> &mut Self {
+        self.max_connection_lifetime = Some(max_connection_lifetime);
+        self
+    }
+
+    /**
+     Stops accepting data once the given amount of rows has been accepted for ingestion, after removing duplicates.
+     Rows beyond the cap are discarded, the main channel receiver stops and the data ingestors drain what was already accepted.
+     * The cap counts accepted rows, not successfully written rows, see `UpsertQuickStream::total_rows`
+     * ***Default behaviour is to accept rows until the main channel is closed***
+     */
+    pub fn max_total_rows(&mut self, max_total_rows: u64) -> &mut Self {
+        self.max_total_rows = Some(max_total_rows);
+        self
+    }
+
+    /**
+     Periodically persists the latest `modified_date` written by the stream with the given query, so a change data capture source can resume from it after a restart.
+     The query is executed with the stream name as `$1` and the watermark as `$2`, e.g.
+     `INSERT INTO watermarks (name, watermark) VALUES ($1, $2) ON CONFLICT (name) DO UPDATE SET watermark = EXCLUDED.watermark`
+     * The watermark is only written while every record pushed to the data ingestors is written, and stops advancing after a batch fails.
+       Resuming from it is at-least-once as long as the producer sends records in `modified_date` order
+     * ***Default behaviour is to not write a watermark***
+     */
+    pub fn watermark_query(&mut self, watermark_query: String, watermark_interval: Duration) -> &mut Self {
+        self.watermark_query = Some((watermark_query, watermark_interval));
+        self
+    }
+
+    /**
+     Spawns the data ingestors, the tasks holding the database connections, the watermark writer and the forwarders of `run_many` on the runtime of the given handle,
+     e.g. a dedicated runtime for ingestion inside a larger application.
+     * ***Default behaviour is to spawn on the runtime `run` is called from***
+     */
+    pub fn runtime_handle(&mut self, runtime_handle: Handle) -> &mut Self {
+        self.runtime_handle = Some(runtime_handle);
+        self
+    }
+
+    /**
+     Calls the given hook every time senders (database connections) are added to or removed from a tier, with the reason of the change.
+     Frequent changes of the same tier usually mean the `connection_creation_threshold` does not fit the load.
+     * The hook is called from the processor loop, keep it short
+     * ***Default behaviour is to only log rebalancing***
+     */
+    pub fn on_rebalance<F>(&mut self, rebalance_hook: F) -> &mut Self where F: Fn(RebalanceEvent) + Send + Sync + 'static {
+        self.rebalance_hook = Some(Arc::new(rebalance_hook));
+        self
+    }
+
+    /**
+     Calls the given hook every time a batch is handed to a sender, before it is written, with the tier, the id of the sender and the size of the batch.
+     For tracing which sender receives which batch, whether or not the batch is written afterwards.
+     * When the data ingestor of the chosen sender already stopped, the batch is handed to the next sender and the hook is called again
+     * The hook is called from the processor loop for every batch, keep it short
+     * ***Default behaviour is to not trace dispatching***
+     */
+    pub fn on_dispatch<F>(&mut self, dispatch_hook: F) -> &mut Self where F: Fn(DispatchEvent) + Send + Sync + 'static {
+        self.dispatch_hook = Some(Arc::new(dispatch_hook));
+        self
+    }
+
+    /**
+     Calls the given hook for every settled batch in the order the batches were dispatched, although the connections write them in parallel.
+     A batch is settled once it is written or failed, see `BatchConfirmation::written`.
+     For checkpointing a source in order, e.g. committing its offsets once every record before them is written.
+     * Every batch gets a sequence number when it is dispatched. A batch settled before an earlier one is held back until the earlier one is settled,
+       at the cost of one entry of a sequence, a row count and the outcome per held back batch. No records are held
+     * A batch which failed to write, or was dropped as its data ingestor stopped or was aborted, is confirmed with `written: false`
+     * The held back entries are not bounded by the channels: while the oldest batch is in flight, every batch settled after it is held back.
+       A hanging data ingestor holds later batches back until it writes, fails or is recreated, see `recreate_stalled_ingestors` and `stalled_ingestor_threshold`
+     * Batches skipped by `dry_run` are confirmed as written. With `strict_fifo` every batch is confirmed right after it is written
+     * The hook is called for every batch from the data ingestors, keep it short
+     * ***Default behaviour is to not confirm batches***
+     */
+    pub fn on_ordered_confirmation<F>(&mut self, confirmation_hook: F) -> &mut Self where F: Fn(BatchConfirmation) + Send + Sync + 'static {
+        self.confirmation_hook = Some(Arc::new(confirmation_hook));
+        self
+    }
+
+    /**
+     Sets the connection, tier and lag parameters for payloads which should be written as soon as possible.
+     Payloads are pushed without lag cycles and a sender is added as soon as a channel is half full, so batches are small
+     and more connections are used for the same load.
+     * Every parameter can still be overridden after the preset
+     */
+    pub fn preset_low_latency(&mut self) -> &mut Self {
+        self.max_connection_count(30)
+            .buffer_size(10)
+            .single_digits(1)
+            .tens(1)
+            .hundreds(1)
+            .max_records_per_cycle_batch(100)
+            .introduced_lag_cycles(0)
+            .introduced_lag_in_millies(1)
+            .connection_creation_threshold(50.0)
+    }
+
+    /**
+     Sets the connection, tier and lag parameters for a high volume of records, favouring large batches over latency.
+     Small payloads wait up to 10 lag cycles of 50 millis to be merged, large buffers absorb bursts and the hundreds start with more senders.
+     * Every parameter can still be overridden after the preset
+     */
+    pub fn preset_high_throughput(&mut self) -> &mut Self {
+        self.max_connection_count(50)
+            .buffer_size(100)
+            .single_digits(1)
+            .tens(2)
+            .hundreds(4)
+            .max_records_per_cycle_batch(1000)
+            .introduced_lag_cycles(10)
+            .introduced_lag_in_millies(50)
+            .connection_creation_threshold(25.0)
+    }
+
+    /**
+     Sets the connection, tier and lag parameters for a database with few connections to spare.
+     Senders are only added once a channel is almost full and kept for at least a minute, so connections are not churned,
+     at the cost of records queueing up in the channels during bursts.
+     * Every parameter can still be overridden after the preset
+     */
+    pub fn preset_conservative_connections(&mut self) -> &mut Self {
+        self.max_connection_count(15)
+            .buffer_size(50)
+            .single_digits(1)
+            .tens(1)
+            .hundreds(1)
+            .max_records_per_cycle_batch(200)
+            .introduced_lag_cycles(5)
+            .introduced_lag_in_millies(20)
+            .connection_creation_threshold(10.0)
+            .connection_removal_cooldown(Duration::from_secs(60))
+    }
+
     pub fn build_update(self) -> UpsertQuickStream {
         trace!("building UpsertQuickStream from builder");
-        UpsertQuickStream {
+        let upsert_quick_stream = UpsertQuickStream {
             cancellation_token: self.cancellation_token.expect("cancellation_token is None"),
-            max_con_count: self.max_con_count.expect("max_con_count is None"),
+            max_con_count: Arc::new(AtomicUsize::new(self.max_con_count.expect("max_con_count is None"))),
             buffer_size: self.buffer_size.expect("buffer_size is None"),
             single_digits: self.single_digits.expect("single_digits is None"),
             tens: self.tens.expect("tens is None"),
             hundreds: self.hundreds.expect("hundreds is None"),
+            fifties: match self.fifties {
+                Some(_) if !self.queries.as_ref().is_some_and(|queries| queries.has_fifty()) => panic!("Query for 50 is None"),
+                Some(_) if self.do_nothing_queries.as_ref().is_some_and(|queries| !queries.has_fifty()) => panic!("Query for 50 is None"),
+                Some(_) if self.dual_write_queries.as_ref().is_some_and(|queries| !queries.has_fifty()) => panic!("Query for 50 is None"),
+                Some(_) if self.variant_queries.values().any(|queries| !queries.has_fifty()) => panic!("Query for 50 is None"),
+                fifties => fifties,
+            },
             db_config: self.db_config.expect("db_config is None"),
-            tls: self.tls,
+            tls: Arc::new(RwLock::new(self.tls)),
             queries: self.queries.expect("queries is None"),
+            conflict_mode: Arc::new(AtomicBool::new(match self.conflict_do_nothing {
+                true => {
+                    self.do_nothing_queries.as_ref().expect("do_nothing_queries is None");
+                    ConflictMode::DoNothing.into()
+                },
+                false => ConflictMode::DoUpdate.into(),
+            })),
+            do_nothing_queries: match self.strict_fifo {
+                true if self.do_nothing_queries.is_some() => panic!("do_nothing_queries is not supported with strict_fifo"),
+                _ => self.do_nothing_queries,
+            },
+            dual_write_queries: match self.strict_fifo {
+                true if self.dual_write_queries.is_some() => panic!("dual_write_queries is not supported with strict_fifo"),
+                _ => self.dual_write_queries,
+            },
+            variant_queries: match self.strict_fifo {
+                true if !self.variant_queries.is_empty() => panic!("variant_queries is not supported with strict_fifo"),
+                _ => self.variant_queries,
+            },
+            returning: match self.strict_fifo {
+                true if self.returning.is_some() => panic!("returning is not supported with strict_fifo"),
+                _ => self.returning,
+            },
             max_records_per_cycle_batch: self.max_records_per_cycle_batch.expect("max_records_per_cycle_batch is None"),
             introduced_lag_cycles: self.introduced_lag_cycles.expect("introduced_lag_cycles is None"),
             introduced_lag_in_millies: self.introduced_lag_in_millies.expect("introduced_lag_in_millies is None"),
-            connection_creation_threshold: self.connection_creation_threshold.expect("connection_creation_threshold is None"),
+            connection_creation_threshold: Arc::new(AtomicU64::new(self.connection_creation_threshold.expect("connection_creation_threshold is None").to_bits())),
+            pending_tunings: Arc::new(Mutex::new(vec![])),
             name: self.name.expect("not a possible scenario"),
-            print_con_config: self.print_connection_configuration
+            print_con_config: self.print_connection_configuration,
+            status_log_level: self.status_log_level,
+            slow_batch_threshold: self.slow_batch_threshold,
+            read_only_reconnect_delay: self.read_only_reconnect_delay,
+            read_only_max_retries: self.read_only_max_retries.unwrap_or(READ_ONLY_MAX_RETRIES),
+            commit_batch_size: self.commit_batch_size,
+            slow_batch_count: Arc::new(AtomicU64::new(0)),
+            stalled_ingestor_threshold: self.stalled_ingestor_threshold,
+            recreate_stalled_ingestors: match self.recreate_stalled_ingestors {
+                true => {
+                    self.stalled_ingestor_threshold.expect("stalled_ingestor_threshold is None");
+                    true
+                },
+                false => false,
+            },
+            stalled_ingestor_count: Arc::new(AtomicU64::new(0)),
+            dry_run: self.dry_run,
+            max_in_flight_records_per_connection: self.max_in_flight_records_per_connection,
+            max_in_flight_records: self.max_in_flight_records,
+            sender_topology: Arc::new(Mutex::new(None)),
+            in_flight_permits: self.max_in_flight_records.map(|max_in_flight_records| Arc::new(Semaphore::new(max_in_flight_records))),
+            in_flight_records: Arc::new(AtomicUsize::new(0)),
+            session_variable: self.session_variable,
+            connect_timeout: self.connect_timeout,
+            max_connection_lifetime: self.max_connection_lifetime,
+            max_total_rows: self.max_total_rows,
+            total_rows: Arc::new(AtomicU64::new(0)),
+            written_rows: Arc::new(AtomicU64::new(0)),
+            rebalance_hook: self.rebalance_hook,
+            dispatch_hook: self.dispatch_hook,
+            confirmation_hook: self.confirmation_hook,
+            confirmation_sequencer: Arc::new(Mutex::new(Default::default())),
+            flush_requested: Arc::new(Notify::new()),
+            state: Arc::new(AtomicU8::new(StreamState::Idle.as_u8())),
+            scale_up_hysteresis: self.scale_up_hysteresis,
+            last_scale_up: Arc::new(Mutex::new(None)),
+            connection_removal_threshold: self.connection_removal_threshold,
+            connection_removal_cooldown: self.connection_removal_cooldown,
+            connection_creation_limiter: self.max_connection_creation_rate
+                .map(|(connections, per)| Arc::new(Mutex::new(TokenBucket::new(connections, per, self.clock.as_deref().unwrap_or(&TokioClock).now())))),
+            too_many_connections_cooldown: self.too_many_connections_cooldown.unwrap_or(TOO_MANY_CONNECTIONS_COOLDOWN),
+            scale_up_paused_until: Arc::new(Mutex::new(None)),
+            scale_ups: Arc::new(Mutex::new(VecDeque::new())),
+            strict_readiness: self.strict_readiness,
+            skip_connectivity_test: self.skip_connectivity_test,
+            adaptive_lag_cycles: self.adaptive_lag_cycles,
+            deterministic: self.deterministic,
+            clock: self.clock,
+            random_state: Arc::new(AtomicU64::new(0)),
+            flush_below_records: self.flush_below_records,
+            watermark_query: self.watermark_query,
+            written_watermark: Arc::new(Mutex::new(None)),
+            watermark_frozen: Arc::new(AtomicBool::new(false)),
+            runtime_handle: self.runtime_handle,
+            max_payload_records: self.max_payload_records,
+            strict_fifo: self.strict_fifo,
+            execution_mode: self.execution_mode.unwrap_or(ExecutionMode::MultiRow),
+            sender_selection: self.sender_selection.unwrap_or(SenderSelection::HighestCapacity),
+            #[cfg(feature = "latency-histogram")]
+            batch_latencies: Arc::new(Mutex::new(Default::default())),
+            expected_columns: match self.verify_schema {
+                true if self.expected_columns.is_empty() => panic!("expected_columns is None"),
+                true => Some(self.expected_columns),
+                false => None,
+            }
+        };
+        // the initial senders are never removed, so they have to fit in the connections
+        let max_con_count = upsert_quick_stream.max_con_count.load(Ordering::SeqCst);
+        let initial_senders = upsert_quick_stream.tiers().iter().map(|(_, init_limit)| init_limit).sum::<usize>();
+        if max_con_count < initial_senders {
+            panic!("max connection count {} is below the {} initial senders of the tiers", max_con_count, initial_senders);
         }
+        upsert_quick_stream
     }
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use log::Level;
     use tokio_postgres::Config;
     use tokio_util::sync::CancellationToken;
 
+    use crate::{error::QuickStreamError, upsert::ConflictMode};
+
     use super::{support::QueryHolder, QuickStreamBuilder};
 
 #[test]
@@ -176,7 +945,7 @@ pub(crate) mod tests {
         let mut builder = QuickStreamBuilder::default();
         builder
             .cancellation_tocken(cancellation_token.clone())
-            .max_connection_count(2)
+            .max_connection_count(32)
             .buffer_size(10)
             .single_digits(2)
             .tens(12)
@@ -191,7 +960,7 @@ pub(crate) mod tests {
 
         let upsert_processor = builder.clone().build_update();
         
-        assert_eq!(upsert_processor.max_con_count, 2);
+        assert_eq!(upsert_processor.max_con_count(), 32);
         assert_eq!(upsert_processor.buffer_size, 10);
         assert_eq!(upsert_processor.single_digits, 2);
         assert_eq!(upsert_processor.tens, 12);
@@ -203,7 +972,7 @@ pub(crate) mod tests {
         assert_eq!(upsert_processor.max_records_per_cycle_batch, 10);
         assert_eq!(upsert_processor.introduced_lag_cycles, 2);
         assert_eq!(upsert_processor.introduced_lag_in_millies, 10);
-        assert_eq!(upsert_processor.connection_creation_threshold, 15.0);
+        assert_eq!(upsert_processor.connection_creation_threshold(), 15.0);
         assert_eq!(upsert_processor.print_con_config, true);
         assert_eq!(upsert_processor.cancellation_token.is_cancelled(), cancellation_token.is_cancelled());
 
@@ -213,6 +982,60 @@ pub(crate) mod tests {
         builder
     }
 
+    #[test]
+    #[should_panic(expected = "max connection count 30 is below the 31 initial senders of the tiers")]
+    fn test_max_con_count_below_initial_senders() {
+        let mut builder = test_builder();
+        builder.max_connection_count(30);
+
+        let _ = builder.build_update();
+    }
+
+    #[test]
+    #[should_panic(expected = "do_nothing_queries is not supported with strict_fifo")]
+    fn test_do_nothing_queries_with_strict_fifo() {
+        let mut builder = test_builder();
+        builder
+            .do_nothing_queries(QueryHolder::default())
+            .strict_fifo();
+
+        let _ = builder.build_update();
+    }
+
+    #[test]
+    #[should_panic(expected = "dual_write_queries is not supported with strict_fifo")]
+    fn test_dual_write_queries_with_strict_fifo() {
+        let mut builder = test_builder();
+        builder
+            .dual_write_queries(QueryHolder::default())
+            .strict_fifo();
+
+        let _ = builder.build_update();
+    }
+
+    #[test]
+    #[should_panic(expected = "returning is not supported with strict_fifo")]
+    fn test_returning_with_strict_fifo() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let mut builder = test_builder();
+        builder
+            .returning(tx)
+            .strict_fifo();
+
+        let _ = builder.build_update();
+    }
+
+    #[test]
+    #[should_panic(expected = "variant_queries is not supported with strict_fifo")]
+    fn test_variant_queries_with_strict_fifo() {
+        let mut builder = test_builder();
+        builder
+            .variant_queries("soft_delete", QueryHolder::default())
+            .strict_fifo();
+
+        let _ = builder.build_update();
+    }
+
     #[test]
     #[should_panic(expected = "cancellation_token is None")]
     fn test_missing_cancellation_token() {
@@ -487,4 +1310,148 @@ pub(crate) mod tests {
 
         let _ = builder.build_update();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_missing_tls_root_cert_file() {
+        let mut builder = QuickStreamBuilder::default();
+        let result = builder.tls_root_cert_file("does/not/exist.crt");
+
+        assert!(matches!(result, Err(QuickStreamError::Io(_))));
+        assert!(builder.tls.is_none());
+    }
+
+    #[test]
+    fn test_presets() {
+        for preset in [QuickStreamBuilder::preset_low_latency, QuickStreamBuilder::preset_high_throughput, QuickStreamBuilder::preset_conservative_connections] {
+            let mut builder = QuickStreamBuilder::default();
+            preset(&mut builder)
+                .cancellation_tocken(CancellationToken::new())
+                .db_config(Config::new())
+                .queries(QueryHolder::default());
+
+            let upsert_processor = builder.build_update();
+            let initial_senders = upsert_processor.tiers().iter().map(|(_, init_limit)| init_limit).sum::<usize>();
+            // leaves room to scale up
+            assert!(upsert_processor.max_con_count() > initial_senders);
+        }
+
+        // parameters set after the preset win
+        let mut builder = QuickStreamBuilder::default();
+        builder.preset_high_throughput().buffer_size(10);
+        assert_eq!(builder.buffer_size, Some(10));
+        assert_eq!(builder.hundreds, Some(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "Query for 50 is None")]
+    fn test_fifties_without_query() {
+        let mut builder = test_builder();
+        builder.fifties(1);
+
+        let _ = builder.build_update();
+    }
+
+    #[test]
+    #[should_panic(expected = "expected_columns is None")]
+    fn test_verify_schema_without_expected_columns() {
+        let mut builder = test_builder();
+        builder.verify_schema();
+
+        let _ = builder.build_update();
+    }
+
+    #[test]
+    fn test_conflict_do_nothing() {
+        let mut builder = test_builder();
+        builder
+            .do_nothing_queries(QueryHolder::default())
+            .conflict_do_nothing();
+
+        let upsert_processor = builder.build_update();
+        assert_eq!(upsert_processor.conflict_mode(), ConflictMode::DoNothing);
+
+        upsert_processor.set_conflict_mode(ConflictMode::DoUpdate);
+        assert_eq!(upsert_processor.conflict_mode(), ConflictMode::DoUpdate);
+    }
+
+    #[test]
+    #[should_panic(expected = "do_nothing_queries is None")]
+    fn test_conflict_do_nothing_without_do_nothing_queries() {
+        let mut builder = test_builder();
+        builder.conflict_do_nothing();
+
+        let _ = builder.build_update();
+    }
+
+    #[test]
+    #[should_panic(expected = "stalled_ingestor_threshold is None")]
+    fn test_recreate_stalled_ingestors_without_threshold() {
+        let mut builder = test_builder();
+        builder.recreate_stalled_ingestors();
+
+        let _ = builder.build_update();
+    }
+
+    #[test]
+    fn test_slow_batch_threshold() {
+        let mut builder = test_builder();
+        builder.slow_batch_threshold(Duration::from_millis(500));
+
+        let upsert_processor = builder.build_update();
+        assert_eq!(upsert_processor.slow_batch_threshold, Some(Duration::from_millis(500)));
+        assert_eq!(upsert_processor.slow_batch_count(), 0);
+        assert_eq!(test_builder().build_update().slow_batch_threshold, None);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let mut builder = test_builder();
+        builder.dry_run();
+
+        assert!(builder.build_update().dry_run);
+        assert!(!test_builder().build_update().dry_run);
+    }
+
+    #[test]
+    fn test_session_variable() {
+        let mut builder = test_builder();
+        builder.session_variable("quick_stream.name".to_string());
+
+        assert_eq!(builder.build_update().session_variable, Some("quick_stream.name".to_string()));
+        assert_eq!(test_builder().build_update().session_variable, None);
+    }
+
+    #[test]
+    fn test_skip_connectivity_test() {
+        let mut builder = test_builder();
+        builder.skip_connectivity_test();
+
+        assert!(builder.build_update().skip_connectivity_test);
+        assert!(!test_builder().build_update().skip_connectivity_test);
+    }
+
+    #[test]
+    fn test_status_log_level() {
+        let mut builder = test_builder();
+        builder.status_log_level(Level::Debug);
+
+        let upsert_processor = builder.build_update();
+        assert_eq!(upsert_processor.status_log_level, Some(Level::Debug));
+        assert_eq!(test_builder().build_update().status_log_level, None);
+    }
+
+    #[test]
+    fn test_shared_db_config() {
+        let mut db_config = Config::new();
+        db_config.host("localhost").dbname("quick_stream");
+        let shared_db_config = Arc::new(db_config.clone());
+
+        let mut borrowed = QuickStreamBuilder::default();
+        borrowed.db_config(&db_config);
+        let mut shared = QuickStreamBuilder::default();
+        shared.db_config(Arc::clone(&shared_db_config));
+
+        assert_eq!(format!("{:?}", borrowed.db_config), format!("{:?}", Some(db_config.clone())));
+        assert_eq!(format!("{:?}", shared.db_config), format!("{:?}", Some(db_config)));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::error::QuickStreamError;
+
 #[derive(Debug, Clone, Default)]
 pub struct QueryHolder {
     one: String,
@@ -10,6 +12,7 @@ pub struct QueryHolder {
     eight: String,
     nine: String,
     ten: String,
+    fifty: Option<String>,
     hundred: String,
 }
 
@@ -24,27 +27,34 @@ impl QueryHolder {
     ///
     /// * `String` - The query string corresponding to the given number.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// This function will panic if `n` is not a valid query number (1-11) or 100.
-    pub(crate) fn get(&self, n: &usize) -> String {
+    /// `MissingTier` if `n` is not a valid query number (1-10, 50 or 100), or is 50 without a query for 50.
+    pub(crate) fn get(&self, n: &usize) -> Result<String, QuickStreamError> {
         match n {
-            1 => self.one.to_owned(),
-            2 => self.two.to_owned(),
-            3 => self.three.to_owned(),
-            4 => self.four.to_owned(),
-            5 => self.five.to_owned(),
-            6 => self.six.to_owned(),
-            7 => self.seven.to_owned(),
-            8 => self.eight.to_owned(),
-            9 => self.nine.to_owned(),
-            10 => self.ten.to_owned(),
-            100 => self.hundred.to_owned(),
-            _ => panic!("Invalid query number: {}", n),
+            1 => Ok(self.one.to_owned()),
+            2 => Ok(self.two.to_owned()),
+            3 => Ok(self.three.to_owned()),
+            4 => Ok(self.four.to_owned()),
+            5 => Ok(self.five.to_owned()),
+            6 => Ok(self.six.to_owned()),
+            7 => Ok(self.seven.to_owned()),
+            8 => Ok(self.eight.to_owned()),
+            9 => Ok(self.nine.to_owned()),
+            10 => Ok(self.ten.to_owned()),
+            50 => self.fifty.to_owned().ok_or(QuickStreamError::MissingTier { tier: 50 }),
+            100 => Ok(self.hundred.to_owned()),
+            _ => Err(QuickStreamError::MissingTier { tier: *n }),
         }
     }
+
+    pub(crate) fn has_fifty(&self) -> bool {
+        self.fifty.is_some()
+    }
 }
 
+/// Builds the queries of every tier. A tier only executes its own query, with exactly `n` records bound,
+/// so each tier can use distinct SQL, e.g. a plain insert for small batches and a merge for batches of a hundred.
 pub struct QueryHolderBuilder {
     one: Option<String>,
     two: Option<String>,
@@ -56,6 +66,7 @@ pub struct QueryHolderBuilder {
     eight: Option<String>,
     nine: Option<String>,
     ten: Option<String>,
+    fifty: Option<String>,
     hundred: Option<String>,
 }
 
@@ -72,6 +83,7 @@ impl QueryHolderBuilder {
             eight: None,
             nine: None,
             ten: None,
+            fifty: None,
             hundred: None,
         }
     }
@@ -126,11 +138,20 @@ impl QueryHolderBuilder {
         self
     }
 
+    /// Query of the optional tier of 50 records, only executed when `QuickStreamBuilder::fifties` is set
+    pub fn set_fifty(&mut self, value: String) -> &mut QueryHolderBuilder {
+        self.fifty = Some(value);
+        self
+    }
+
     pub fn set_hundred(&mut self, value: String) -> &mut QueryHolderBuilder {
         self.hundred = Some(value);
         self
     }
 
+    /// # Panics
+    ///
+    /// This function will panic if a query is missing or blank. The query for 50 is optional.
     pub fn build(&self) -> QueryHolder {
         if self.one.is_none()
             || self.two.is_none()
@@ -146,6 +167,16 @@ impl QueryHolderBuilder {
         {
             panic!("Some Queries Are Missing")
         } else {
+            let queries = [&self.one, &self.two, &self.three, &self.four, &self.five, &self.six, &self.seven, &self.eight, &self.nine, &self.ten, &self.hundred];
+            for (query, n) in queries.iter().zip([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 100]) {
+                if query.as_ref().unwrap().trim().is_empty() {
+                    panic!("Query for {} is blank", n)
+                }
+            }
+            if self.fifty.as_ref().is_some_and(|fifty| fifty.trim().is_empty()) {
+                panic!("Query for 50 is blank")
+            }
+
             QueryHolder {
                 one: self.one.clone().unwrap(),
                 two: self.two.clone().unwrap(),
@@ -157,8 +188,66 @@ impl QueryHolderBuilder {
                 eight: self.eight.clone().unwrap(),
                 nine: self.nine.clone().unwrap(),
                 ten: self.ten.clone().unwrap(),
+                fifty: self.fifty.clone(),
                 hundred: self.hundred.clone().unwrap(),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::error::QuickStreamError;
+
+    use super::QueryHolderBuilder;
+
+    fn query_holder_builder() -> QueryHolderBuilder {
+        let mut query_holder_builder = QueryHolderBuilder::new();
+        query_holder_builder
+            .set_one("one".to_string())
+            .set_two("two".to_string())
+            .set_three("three".to_string())
+            .set_four("four".to_string())
+            .set_five("five".to_string())
+            .set_six("six".to_string())
+            .set_seven("seven".to_string())
+            .set_eight("eight".to_string())
+            .set_nine("nine".to_string())
+            .set_ten("ten".to_string())
+            .set_hundred("hundred".to_string());
+
+        query_holder_builder
+    }
+
+    #[test]
+    fn test_every_tier_gets_its_own_query() {
+        let queries = query_holder_builder().build();
+
+        let expected = ["one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten"];
+        for (n, query) in (1..=10).zip(expected) {
+            assert_eq!(queries.get(&n).unwrap(), query);
+        }
+        assert_eq!(queries.get(&100).unwrap(), "hundred");
+    }
+
+    #[test]
+    fn test_query_of_missing_tier() {
+        let queries = query_holder_builder().build();
+
+        assert!(matches!(queries.get(&11), Err(QuickStreamError::MissingTier { tier: 11 })));
+        assert!(matches!(queries.get(&50), Err(QuickStreamError::MissingTier { tier: 50 })));
+    }
+
+    #[test]
+    #[should_panic(expected = "Query for 100 is blank")]
+    fn test_blank_query() {
+        query_holder_builder().set_hundred("  ".to_string()).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "Some Queries Are Missing")]
+    fn test_missing_query() {
+        let mut query_holder_builder = QueryHolderBuilder::new();
+        query_holder_builder.set_one("one".to_string()).build();
+    }
+}
@@ -0,0 +1,109 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use futures::future::BoxFuture;
+use tokio::sync::Notify;
+
+/**
+ Source of time for every time based decision of the stream, e.g. the lag cycles, the age of connections or stalled data ingestors.
+ Set with `QuickStreamBuilder::clock`, so tests can drive the stream through time instead of waiting for it.
+ * Timeouts of the database client itself, e.g. `connect_timeout` of the `db_config`, are not driven by the clock
+ */
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/**
+ The clock of the tokio runtime, used by default.
+ * Follows `tokio::time::pause` and `tokio::time::advance`, which makes it the simplest clock to control in `#[tokio::test(start_paused = true)]` tests
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[derive(Debug)]
+struct ManualClockState {
+    started: Instant,
+    elapsed: Mutex<Duration>,
+    advanced: Notify,
+}
+
+/**
+ A clock which only moves when advanced, for tests running without a paused tokio runtime.
+ * Clones share the same time, keep one to advance the clock given to the builder
+ * Sleeps complete once the clock was advanced past their deadline
+ */
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    state: Arc<ManualClockState>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self { state: Arc::new(ManualClockState { started: Instant::now(), elapsed: Mutex::new(Duration::ZERO), advanced: Notify::new() }) }
+    }
+
+    /// Moves the clock forward, waking every sleep whose deadline passed
+    pub fn advance(&self, duration: Duration) {
+        *self.state.elapsed.lock().unwrap() += duration;
+        self.state.advanced.notify_waiters();
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.state.started + *self.state.elapsed.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let clock = self.clone();
+        let deadline = self.now() + duration;
+        Box::pin(async move {
+            loop {
+                // registered before checking the time, so an advance in between is not missed
+                let advanced = clock.state.advanced.notified();
+                if clock.now() >= deadline {
+                    return
+                }
+                advanced.await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use super::{Clock, ManualClock};
+
+    #[tokio::test]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let started = clock.now();
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+
+        clock.advance(Duration::from_secs(4));
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_secs(6));
+        sleep.await;
+        assert_eq!(clock.now() - started, Duration::from_secs(10));
+    }
+}
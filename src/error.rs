@@ -0,0 +1,148 @@
+use std::{error::Error, fmt::Display, io, time::Duration};
+
+use tokio_postgres::error::SqlState;
+
+/**
+ Errors of the stream. Failures caused by the database, its connections or the data ingestors are returned or logged, not panicked.
+ * Panics are left for misuse caught while configuring, e.g. `build_update` without a required parameter or
+   `set_conflict_mode` without `do_nothing_queries`
+ * Broken internal invariants which can be reported, e.g. a batch size without a tier, fail with `MissingTier` or `SendersClosed`.
+   The others are `debug_assert!`ed and logged in release builds
+ * A data ingestor stopping on an error or a panic of `Upsert::upsert` is logged and removed when its tier is rebalanced, the stream keeps running.
+   Only when every data ingestor of a tier stopped, `run` fails with `SendersClosed`
+ * A panicking hook, e.g. of `on_rebalance`, panics the task calling it
+ */
+#[derive(Debug)]
+pub enum QuickStreamError {
+    /// A certificate could not be parsed or the tls connector could not be built with it
+    Tls(native_tls::Error),
+    /// An error returned by the database or the connection to it
+    Database(tokio_postgres::Error),
+    /// The database connection could not be established within the configured connect timeout
+    ConnectTimeout(Duration),
+    /// A file given to the builder, e.g. a certificate, could not be read
+    Io(io::Error),
+    /// A data ingestor stopped before it was ready, e.g. it panicked or failed to prepare its statements
+    IngestorStopped { tier: usize, id: i64 },
+    /// The query of a tier could not be prepared, e.g. it has a typo or refers to an unknown table or column
+    Prepare { tier: usize, query: String, source: tokio_postgres::Error },
+    /// Data ingestors failed to get ready with `strict_readiness`, holding the first error of every failed tier
+    NotReady(Vec<QuickStreamError>),
+    /// Tables checked with `verify_schema` differ from their expected columns, describing every difference
+    SchemaMismatch(Vec<String>),
+    /// A parameter passed to `UpsertQuickStream::tune` is out of its valid range
+    InvalidTuning(String),
+    /// Postgres rejected a batch as several of its rows hit the same conflict target, although their `Upsert::conflict_key` differs
+    DuplicateConflictTarget { tier: usize, source: tokio_postgres::Error },
+    /// Every data ingestor of a tier stopped, so a batch of the tier could not be dispatched
+    SendersClosed { tier: usize },
+    /// Batches dispatched before a flush failed to write, see `UpsertQuickStream::flush`
+    FlushFailed { failed_batches: u64 },
+    /// A batch or a data ingestor refers to a tier without a query or senders, a broken invariant of the crate reported instead of panicking
+    MissingTier { tier: usize },
+    /// A batch holds records of an `Upsert::statement_variant` without `QuickStreamBuilder::variant_queries`, stopping its data ingestor
+    MissingVariant { variant: String },
+    /// `QuickStreamBuilder::returning` is set for records which do not implement `Upsert::upsert_returning`, see `Upsert::RETURNING`
+    ReturningUnsupported,
+}
+
+impl Display for QuickStreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuickStreamError::Tls(error) => write!(f, "tls error : {}", error),
+            QuickStreamError::Database(error) => write!(f, "database error : {}", error),
+            QuickStreamError::ConnectTimeout(timeout) => write!(f, "timed out after {:?} while establishing database connection", timeout),
+            QuickStreamError::Io(error) => write!(f, "io error : {}", error),
+            QuickStreamError::IngestorStopped { tier, id } => write!(f, "data ingestor {}:{} stopped before it was ready", tier, id),
+            QuickStreamError::Prepare { tier, query, source } => write!(f, "failed to prepare query of tier {} : {}. query : {}", tier, source, query),
+            QuickStreamError::DuplicateConflictTarget { tier, source } => write!(f, "rows of a batch of tier {} share a conflict target, Upsert::conflict_key does not match the ON CONFLICT target : {}", tier, source),
+            QuickStreamError::InvalidTuning(reason) => write!(f, "invalid tuning : {}", reason),
+            QuickStreamError::SchemaMismatch(mismatches) => write!(f, "schema verification failed : [{}]", mismatches.join(", ")),
+            QuickStreamError::SendersClosed { tier } => write!(f, "every data ingestor of tier {} stopped, unable to dispatch a batch", tier),
+            QuickStreamError::FlushFailed { failed_batches } => write!(f, "{} batches failed to write before the flush", failed_batches),
+            QuickStreamError::MissingTier { tier } => write!(f, "tier {} has no query or senders", tier),
+            QuickStreamError::MissingVariant { variant } => write!(f, "statement variant {} has no variant_queries", variant),
+            QuickStreamError::ReturningUnsupported => write!(f, "returning is set, but Upsert::upsert_returning is not implemented"),
+            QuickStreamError::NotReady(errors) => write!(f, "data ingestors of {} tiers failed to get ready : [{}]", errors.len(), errors.iter().map(|error| error.to_string()).collect::<Vec<String>>().join(", ")),
+        }
+    }
+}
+
+impl Error for QuickStreamError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            QuickStreamError::Tls(error) => Some(error),
+            QuickStreamError::Database(error) => Some(error),
+            QuickStreamError::ConnectTimeout(_) => None,
+            QuickStreamError::Io(error) => Some(error),
+            QuickStreamError::IngestorStopped { .. } => None,
+            QuickStreamError::Prepare { source, .. } => Some(source),
+            QuickStreamError::NotReady(_) => None,
+            QuickStreamError::SchemaMismatch(_) => None,
+            QuickStreamError::InvalidTuning(_) => None,
+            QuickStreamError::DuplicateConflictTarget { source, .. } => Some(source),
+            QuickStreamError::SendersClosed { .. } => None,
+            QuickStreamError::FlushFailed { .. } => None,
+            QuickStreamError::MissingTier { .. } => None,
+            QuickStreamError::MissingVariant { .. } => None,
+            QuickStreamError::ReturningUnsupported => None,
+        }
+    }
+}
+
+impl QuickStreamError {
+    /// Whether the database refused the connection as it reached its `max_connections`, e.g. `FATAL: sorry, too many clients already`
+    pub fn is_too_many_connections(&self) -> bool {
+        match self {
+            QuickStreamError::Database(error) => error.code() == Some(&SqlState::TOO_MANY_CONNECTIONS),
+            _ => false,
+        }
+    }
+}
+
+impl From<native_tls::Error> for QuickStreamError {
+    fn from(error: native_tls::Error) -> Self {
+        QuickStreamError::Tls(error)
+    }
+}
+
+impl From<io::Error> for QuickStreamError {
+    fn from(error: io::Error) -> Self {
+        QuickStreamError::Io(error)
+    }
+}
+
+impl From<tokio_postgres::Error> for QuickStreamError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        QuickStreamError::Database(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, time::Duration};
+
+    use super::QuickStreamError;
+
+    #[test]
+    fn test_is_too_many_connections() {
+        assert!(!QuickStreamError::ConnectTimeout(Duration::from_secs(1)).is_too_many_connections());
+        assert!(!QuickStreamError::SendersClosed { tier: 1 }.is_too_many_connections());
+    }
+
+    #[test]
+    fn test_connect_timeout_display() {
+        let error = QuickStreamError::ConnectTimeout(Duration::from_millis(50));
+        assert_eq!(error.to_string(), "timed out after 50ms while establishing database connection");
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn test_not_ready_display() {
+        let error = QuickStreamError::NotReady(vec![
+            QuickStreamError::IngestorStopped { tier: 1, id: 0 },
+            QuickStreamError::ConnectTimeout(Duration::from_secs(1)),
+        ]);
+        assert_eq!(error.to_string(), "data ingestors of 2 tiers failed to get ready : [data ingestor 1:0 stopped before it was ready, timed out after 1s while establishing database connection]");
+    }
+}
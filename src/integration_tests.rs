@@ -0,0 +1,736 @@
+use std::{ops::Range, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime};
+use futures::future::BoxFuture;
+use testcontainers::{runners::AsyncRunner, ContainerAsync};
+use testcontainers_modules::postgres::Postgres;
+use tokio::sync::mpsc;
+use tokio_postgres::{types::ToSql, Client, Config, Error, NoTls, Row, Statement};
+use tokio_util::sync::CancellationToken;
+
+use crate::builder::{support::{QueryHolder, QueryHolderBuilder}, QuickStreamBuilder};
+use crate::error::QuickStreamError;
+use crate::upsert::{ExecutionMode, StreamState, Upsert};
+
+#[derive(Clone, Debug)]
+struct TestData {
+    id: i64,
+    modified_date: NaiveDateTime,
+}
+
+#[async_trait]
+impl Upsert<TestData> for TestData {
+    fn upsert<'a>(
+        client: &'a Client,
+        data: Vec<TestData>,
+        statement: &'a Statement,
+        _thread_id: i64,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(data.len() * 2);
+            for row in &data {
+                params.push(&row.id);
+                params.push(&row.modified_date);
+            }
+            client.execute(statement, &params).await
+        })
+    }
+
+    fn upsert_returning<'a>(
+        client: &'a Client,
+        data: Vec<TestData>,
+        statement: &'a Statement,
+        _thread_id: i64,
+    ) -> BoxFuture<'a, Result<Vec<Row>, Error>> {
+        Box::pin(async move {
+            let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(data.len() * 2);
+            for row in &data {
+                params.push(&row.id);
+                params.push(&row.modified_date);
+            }
+            client.query(statement, &params).await
+        })
+    }
+
+    const RETURNING: bool = true;
+
+    fn modified_date(&self) -> NaiveDateTime {
+        self.modified_date
+    }
+
+    fn pkey(&self) -> i64 {
+        self.id
+    }
+}
+
+fn test_data(ids: Range<i64>, timestamp: i64) -> Vec<TestData> {
+    ids.map(|id| TestData { id, modified_date: DateTime::from_timestamp(timestamp, 0).unwrap().naive_utc() }).collect()
+}
+
+fn upsert_query(table: &str, n: usize) -> String {
+    let values = (0..n).map(|i| format!("(${}, ${})", i * 2 + 1, i * 2 + 2)).collect::<Vec<String>>().join(", ");
+    format!("INSERT INTO {} (id, modified_date) VALUES {} ON CONFLICT (id) DO UPDATE SET modified_date = EXCLUDED.modified_date", table, values)
+}
+
+fn returning_upsert_query(table: &str, n: usize) -> String {
+    format!("{} RETURNING id", upsert_query(table, n))
+}
+
+/// Records which tier wrote a row, to verify each tier executes its own query
+fn tier_upsert_query(table: &str, n: usize) -> String {
+    let values = (0..n).map(|i| format!("(${}, ${}, {})", i * 2 + 1, i * 2 + 2, n)).collect::<Vec<String>>().join(", ");
+    format!("INSERT INTO {} (id, modified_date, tier) VALUES {} ON CONFLICT (id) DO UPDATE SET modified_date = EXCLUDED.modified_date, tier = EXCLUDED.tier", table, values)
+}
+
+fn build_query_holder(table: &str, query: fn(&str, usize) -> String) -> QueryHolder {
+    let mut query_holder_builder = QueryHolderBuilder::new();
+    query_holder_builder
+        .set_one(query(table, 1))
+        .set_two(query(table, 2))
+        .set_three(query(table, 3))
+        .set_four(query(table, 4))
+        .set_five(query(table, 5))
+        .set_six(query(table, 6))
+        .set_seven(query(table, 7))
+        .set_eight(query(table, 8))
+        .set_nine(query(table, 9))
+        .set_ten(query(table, 10))
+        .set_hundred(query(table, 100));
+
+    query_holder_builder.build()
+}
+
+fn query_holder(table: &str) -> QueryHolder {
+    build_query_holder(table, upsert_query)
+}
+
+async fn start_postgres() -> (ContainerAsync<Postgres>, Config) {
+    let container = Postgres::default().start().await.expect("failed to start postgres container");
+    let host = container.get_host().await.unwrap();
+    let port = container.get_host_port_ipv4(5432).await.unwrap();
+
+    let mut config = Config::new();
+    config
+        .host(host.to_string().as_str())
+        .port(port)
+        .user("postgres")
+        .password("postgres")
+        .dbname("postgres")
+        .connect_timeout(Duration::from_secs(30));
+
+    (container, config)
+}
+
+async fn connect(config: &Config) -> Client {
+    let (client, connection) = config.connect(NoTls).await.unwrap();
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    client
+}
+
+fn quick_stream_builder(config: Config, table: &str) -> QuickStreamBuilder {
+    let mut quick_stream_builder = QuickStreamBuilder::default();
+    quick_stream_builder
+        .cancellation_tocken(CancellationToken::new())
+        .max_connection_count(20)
+        .buffer_size(10)
+        .single_digits(1)
+        .tens(1)
+        .hundreds(1)
+        .db_config(config)
+        .queries(query_holder(table))
+        .max_records_per_cycle_batch(100)
+        .introduced_lag_cycles(2)
+        .introduced_lag_in_millies(10)
+        .connection_creation_threshold(25.0);
+
+    quick_stream_builder
+}
+
+/// Ingestors keep writing after `run` returns, so poll until the expected amount of rows shows up.
+async fn wait_for_count(client: &Client, query: &str, expected: i64) -> i64 {
+    let mut count = 0;
+    for _ in 0..60 {
+        count = client.query_one(query, &[]).await.unwrap().get::<usize, i64>(0);
+        if count == expected {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    count
+}
+
+#[tokio::test]
+async fn test_ingestion_across_all_tiers() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test1 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let upsert_quick_stream = quick_stream_builder(config, "test1").build_update();
+    let (tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream.run(rx).await.unwrap();
+    });
+
+    // 256 records are split into 2 batches of 100, 5 batches of 10 and a batch of 6
+    tx.send(test_data(0..256, 1627847280)).await.unwrap();
+    // a small payload goes through the lag cycles instead
+    tx.send(test_data(256..259, 1627847280)).await.unwrap();
+    drop(tx);
+    handle.await.unwrap();
+
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test1", 259).await, 259);
+}
+
+#[tokio::test]
+async fn test_ingestion_keeps_latest_duplicate() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test2 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let upsert_quick_stream = quick_stream_builder(config, "test2").build_update();
+    let (tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream.run(rx).await.unwrap();
+    });
+
+    let mut data = test_data(0..60, 1627847280);
+    data.append(&mut test_data(0..60, 1627847290));
+    tx.send(data).await.unwrap();
+    drop(tx);
+    handle.await.unwrap();
+
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test2 WHERE modified_date = '2021-08-01 19:48:10'", 60).await, 60);
+}
+
+#[tokio::test]
+async fn test_lag_cycle_removes_duplicates_before_splitting() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test9 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let upsert_quick_stream = quick_stream_builder(config, "test9").build_update();
+    let (tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream.run(rx).await.unwrap();
+    });
+
+    // 90 records of 3 primary keys stay below max records per cycle batch and go through the lag cycles. Without removing
+    // the duplicates first they would be split into batches of 10 which postgres rejects, as one upsert can not affect a row twice
+    let data = (0..30).flat_map(|i| test_data(0..3, 1627847280 + i)).collect::<Vec<TestData>>();
+    tx.send(data).await.unwrap();
+    drop(tx);
+    handle.await.unwrap();
+
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test9 WHERE modified_date = '2021-08-01 19:48:29'", 3).await, 3);
+}
+
+#[tokio::test]
+async fn test_strict_readiness_fails_on_missing_table() {
+    let (_container, config) = start_postgres().await;
+
+    let mut quick_stream_builder = quick_stream_builder(config, "missing");
+    quick_stream_builder.strict_readiness();
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (_tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+
+    // every tier fails with its own query
+    match upsert_quick_stream.run(rx).await {
+        Err(QuickStreamError::NotReady(errors)) => {
+            assert_eq!(errors.len(), 11);
+            assert!(errors.iter().all(|error| matches!(error, QuickStreamError::Prepare { query, .. } if query.contains("missing"))));
+        },
+        result => panic!("expected data ingestors not to be ready, got {:?}", result),
+    }
+}
+
+#[tokio::test]
+async fn test_strict_fifo() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test11 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let mut quick_stream_builder = quick_stream_builder(config, "test11");
+    quick_stream_builder.strict_fifo();
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream.run(rx).await.unwrap();
+    });
+
+    tx.send(test_data(0..256, 1627847290)).await.unwrap();
+    // produced later with an older modified date, it is still written last
+    tx.send(test_data(0..3, 1627847280)).await.unwrap();
+    drop(tx);
+    // returns once everything is written, as nothing is written in parallel
+    handle.await.unwrap();
+
+    let count = client.query_one("SELECT COUNT(*) FROM test11 WHERE modified_date = '2021-08-01 19:48:10'", &[]).await.unwrap().get::<usize, i64>(0);
+    assert_eq!(count, 253);
+}
+
+#[tokio::test]
+async fn test_verify_schema_reports_every_mismatch() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test10 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let mut quick_stream_builder = quick_stream_builder(config, "test10");
+    quick_stream_builder
+        .verify_schema()
+        .expected_columns("public.test10".to_string(), vec![("id".to_string(), "int8".to_string()), ("modified_date".to_string(), "date".to_string()), ("name".to_string(), "text".to_string())])
+        .expected_columns("missing".to_string(), vec![("id".to_string(), "bigint".to_string())]);
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (_tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+
+    match upsert_quick_stream.run(rx).await {
+        Err(QuickStreamError::SchemaMismatch(mismatches)) => assert_eq!(mismatches, vec![
+            "column public.test10.modified_date is timestamp without time zone (timestamp), expected date".to_string(),
+            "column public.test10.name is missing".to_string(),
+            "table missing does not exist or has no columns".to_string(),
+        ]),
+        result => panic!("expected a schema mismatch, got {:?}", result),
+    }
+}
+
+#[tokio::test]
+async fn test_every_tier_executes_its_own_query() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test4 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL, tier INT NOT NULL)").await.unwrap();
+
+    let mut quick_stream_builder = quick_stream_builder(config, "test4");
+    quick_stream_builder.queries(build_query_holder("test4", tier_upsert_query));
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream.run(rx).await.unwrap();
+    });
+
+    // 100 records go through the hundreds tier, 10 through the tens tier and 7 through the single digits tier
+    tx.send(test_data(0..117, 1627847280)).await.unwrap();
+    drop(tx);
+    handle.await.unwrap();
+
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test4", 117).await, 117);
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test4 WHERE tier = 100", 100).await, 100);
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test4 WHERE tier = 10", 10).await, 10);
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test4 WHERE tier = 7", 7).await, 7);
+}
+
+#[tokio::test]
+async fn test_single_row_pipelined_execution() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test12 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL, tier INT NOT NULL)").await.unwrap();
+
+    let mut quick_stream_builder = quick_stream_builder(config, "test12");
+    quick_stream_builder
+        .queries(build_query_holder("test12", tier_upsert_query))
+        .execution_mode(ExecutionMode::SingleRowPipelined);
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream.run(rx).await.unwrap();
+    });
+
+    tx.send(test_data(0..117, 1627847280)).await.unwrap();
+    drop(tx);
+    handle.await.unwrap();
+
+    // every tier executes the query of tier 1, once per record
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test12 WHERE tier = 1", 117).await, 117);
+}
+
+#[tokio::test]
+async fn test_dual_write() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test13 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+    client.batch_execute("CREATE TABLE test13_v2 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL, tier INT NOT NULL)").await.unwrap();
+
+    let mut quick_stream_builder = quick_stream_builder(config, "test13");
+    quick_stream_builder.dual_write_queries(build_query_holder("test13_v2", tier_upsert_query));
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream.run(rx).await.unwrap();
+    });
+
+    tx.send(test_data(0..117, 1627847280)).await.unwrap();
+    drop(tx);
+    handle.await.unwrap();
+
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test13", 117).await, 117);
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test13_v2", 117).await, 117);
+}
+
+#[tokio::test]
+async fn test_returning() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test14 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let (returning_tx, mut returning_rx) = mpsc::channel::<Vec<Row>>(100);
+    let mut quick_stream_builder = quick_stream_builder(config, "test14");
+    quick_stream_builder
+        .queries(build_query_holder("test14", returning_upsert_query))
+        .returning(returning_tx);
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, handle) = upsert_quick_stream.start::<TestData>();
+    drop(upsert_quick_stream);
+
+    tx.send(test_data(0..117, 1627847280)).await.unwrap();
+    drop(tx);
+    handle.completed().await.unwrap();
+
+    // closes once every clone of the stream holding the returning channel is dropped
+    let mut ids = vec![];
+    while let Some(rows) = returning_rx.recv().await {
+        ids.extend(rows.iter().map(|row| row.get::<usize, i64>(0)));
+    }
+    ids.sort();
+    assert_eq!(ids, (0..117).collect::<Vec<i64>>());
+}
+
+#[tokio::test]
+async fn test_commit_batch_size() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test15 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let mut quick_stream_builder = quick_stream_builder(config, "test15");
+    quick_stream_builder.commit_batch_size(4);
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, handle) = upsert_quick_stream.start::<TestData>();
+
+    tx.send(test_data(0..347, 1627847280)).await.unwrap();
+    drop(tx);
+    let shutdown_report = handle.completed().await.unwrap();
+
+    assert_eq!(shutdown_report.rows_written, 347);
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test15", 347).await, 347);
+}
+
+#[tokio::test]
+async fn test_max_connection_lifetime() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test16 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    // every batch is written with a new connection
+    let mut quick_stream_builder = quick_stream_builder(config, "test16");
+    quick_stream_builder.max_connection_lifetime(Duration::ZERO);
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, handle) = upsert_quick_stream.start::<TestData>();
+
+    for batch in 0..5 {
+        tx.send(test_data(batch * 100..batch * 100 + 100, 1627847280)).await.unwrap();
+    }
+    drop(tx);
+    let shutdown_report = handle.completed().await.unwrap();
+
+    assert_eq!(shutdown_report.rows_written, 500);
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test16", 500).await, 500);
+}
+
+#[tokio::test]
+async fn test_start() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test5 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let upsert_quick_stream = quick_stream_builder(config, "test5").build_update();
+    let (tx, handle) = upsert_quick_stream.start::<TestData>();
+
+    tx.send(test_data(0..150, 1627847280)).await.unwrap();
+    drop(tx);
+    handle.join().await.unwrap();
+
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test5", 150).await, 150);
+}
+
+#[tokio::test]
+async fn test_cancellation_drains_data_ingestors() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test6 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let cancellation_token = CancellationToken::new();
+    let mut quick_stream_builder = quick_stream_builder(config, "test6");
+    quick_stream_builder.cancellation_tocken(cancellation_token.clone());
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let upsert_quick_stream_clone = upsert_quick_stream.clone();
+    let (tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream_clone.run(rx).await.unwrap()
+    });
+
+    tx.send(test_data(0..150, 1627847280)).await.unwrap();
+    while upsert_quick_stream.total_rows() < 150 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(upsert_quick_stream.state(), StreamState::Running);
+    cancellation_token.cancel();
+    let shutdown_report = handle.await.unwrap();
+    assert_eq!(upsert_quick_stream.state(), StreamState::Stopped);
+    assert!(shutdown_report.drained);
+    assert_eq!(shutdown_report.rows_accepted, 150);
+    assert_eq!(shutdown_report.rows_written, 150);
+
+    // run only returns after the data ingestors drained, so every row is written already
+    let count = client.query_one("SELECT COUNT(*) FROM test6", &[]).await.unwrap().get::<usize, i64>(0);
+    assert_eq!(count, 150);
+}
+
+#[tokio::test]
+async fn test_cancellation_flushes_lag_cycle() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test7 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let cancellation_token = CancellationToken::new();
+    let mut quick_stream_builder = quick_stream_builder(config, "test7");
+    quick_stream_builder
+        .cancellation_tocken(cancellation_token.clone())
+        // keeps small payloads buffered in the lag cycle for a minute
+        .introduced_lag_cycles(600)
+        .introduced_lag_in_millies(100);
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, rx) = mpsc::channel::<Vec<TestData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream.run(rx).await.unwrap();
+    });
+
+    tx.send(test_data(0..5, 1627847280)).await.unwrap();
+    // gives the stream time to start and receive the payload
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    cancellation_token.cancel();
+    handle.await.unwrap();
+
+    let count = client.query_one("SELECT COUNT(*) FROM test7", &[]).await.unwrap().get::<usize, i64>(0);
+    assert_eq!(count, 5);
+}
+
+#[tokio::test]
+async fn test_flush() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test17 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let mut quick_stream_builder = quick_stream_builder(config, "test17");
+    // keeps small payloads buffered in the lag cycle for a minute
+    quick_stream_builder
+        .introduced_lag_cycles(600)
+        .introduced_lag_in_millies(100);
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, handle) = upsert_quick_stream.start::<TestData>();
+
+    // an idle stream flushes right away
+    upsert_quick_stream.flush().await.unwrap();
+
+    for batch in 0..3 {
+        tx.send(test_data(batch * 5..batch * 5 + 5, 1627847280)).await.unwrap();
+    }
+    upsert_quick_stream.flush().await.unwrap();
+    // written before the lag cycles ran out, without closing the stream
+    let count = client.query_one("SELECT COUNT(*) FROM test17", &[]).await.unwrap().get::<usize, i64>(0);
+    assert_eq!(count, 15);
+    assert_eq!(upsert_quick_stream.state(), StreamState::Running);
+
+    tx.send(test_data(15..20, 1627847280)).await.unwrap();
+    upsert_quick_stream.flush().await.unwrap();
+    let count = client.query_one("SELECT COUNT(*) FROM test17", &[]).await.unwrap().get::<usize, i64>(0);
+    assert_eq!(count, 20);
+
+    drop(tx);
+    handle.completed().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_read_only_retries() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test18 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+    // every new connection is read only, as on a demoted primary
+    client.batch_execute("ALTER DATABASE postgres SET default_transaction_read_only = on").await.unwrap();
+
+    let mut retrying_builder = quick_stream_builder(config.clone(), "test18");
+    retrying_builder
+        .read_only_reconnect_delay(Duration::from_millis(10))
+        .read_only_max_retries(2);
+    let upsert_quick_stream = retrying_builder.build_update();
+    let (tx, handle) = upsert_quick_stream.start::<TestData>();
+
+    tx.send(test_data(0..5, 1627847280)).await.unwrap();
+    // the batch fails once the retries are used up, instead of being retried forever
+    assert!(matches!(upsert_quick_stream.flush().await, Err(QuickStreamError::FlushFailed { failed_batches: 1 })));
+    drop(tx);
+    handle.join().await.unwrap();
+
+    let cancellation_token = CancellationToken::new();
+    let mut quick_stream_builder = quick_stream_builder(config, "test18");
+    quick_stream_builder
+        .cancellation_tocken(cancellation_token.clone())
+        .read_only_reconnect_delay(Duration::from_secs(3600));
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, handle) = upsert_quick_stream.start::<TestData>();
+
+    tx.send(test_data(0..5, 1627847280)).await.unwrap();
+    while upsert_quick_stream.total_rows() < 5 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // shutdown does not wait out the delay before the next retry
+    cancellation_token.cancel();
+    tokio::time::timeout(Duration::from_secs(30), handle.join()).await.unwrap().unwrap();
+    drop(tx);
+
+    client.batch_execute("ALTER DATABASE postgres SET default_transaction_read_only = off").await.unwrap();
+    let count = client.query_one("SELECT COUNT(*) FROM test18", &[]).await.unwrap().get::<usize, i64>(0);
+    assert_eq!(count, 0);
+}
+
+#[tokio::test]
+async fn test_too_many_connections() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("
+        CREATE TABLE test19 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL);
+        CREATE ROLE limited LOGIN PASSWORD 'limited' CONNECTION LIMIT 3;
+        GRANT ALL ON test19 TO limited;
+    ").await.unwrap();
+    let mut limited_config = config.clone();
+    limited_config.user("limited").password("limited");
+
+    let mut quick_stream_builder = quick_stream_builder(limited_config.clone(), "test19");
+    // one sender per tier takes every connection of the role, every batch tries to scale up
+    quick_stream_builder
+        .connection_creation_threshold(100.0)
+        .strict_readiness()
+        .skip_connectivity_test();
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, handle) = upsert_quick_stream.start::<TestData>();
+    while upsert_quick_stream.state() != StreamState::Running {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let error = limited_config.connect(NoTls).await.map(|_| ()).unwrap_err();
+    assert!(QuickStreamError::from(error).is_too_many_connections());
+
+    tx.send(test_data(0..150, 1627847280)).await.unwrap();
+    upsert_quick_stream.flush().await.unwrap();
+    // the batches of the refused senders were written by the existing ones
+    let count = client.query_one("SELECT COUNT(*) FROM test19", &[]).await.unwrap().get::<usize, i64>(0);
+    assert_eq!(count, 150);
+
+    drop(tx);
+    handle.completed().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_max_total_rows_with_max_payload_records() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TABLE test20 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL)").await.unwrap();
+
+    let mut quick_stream_builder = quick_stream_builder(config, "test20");
+    // the remainder of a chunked payload would wait a minute in the lag cycles
+    quick_stream_builder
+        .max_payload_records(100)
+        .max_total_rows(150)
+        .introduced_lag_cycles(600)
+        .introduced_lag_in_millies(100);
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, handle) = upsert_quick_stream.start::<TestData>();
+
+    tx.send(test_data(0..250, 1627847280)).await.unwrap();
+    let shutdown_report = tokio::time::timeout(Duration::from_secs(10), handle.join()).await.unwrap().unwrap();
+    assert_eq!(shutdown_report.rows_accepted, 150);
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test20", 150).await, 150);
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Mood {
+    Happy,
+    Sad,
+}
+
+impl Mood {
+    fn label(&self) -> &'static str {
+        match self {
+            Mood::Happy => "happy",
+            Mood::Sad => "sad",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct MoodData {
+    id: i64,
+    modified_date: NaiveDateTime,
+    mood: Mood,
+}
+
+#[async_trait]
+impl Upsert<MoodData> for MoodData {
+    fn upsert<'a>(
+        client: &'a Client,
+        data: Vec<MoodData>,
+        statement: &'a Statement,
+        _thread_id: i64,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let moods = data.iter().map(|row| row.mood.label()).collect::<Vec<&str>>();
+            let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(data.len() * 3);
+            for (row, mood) in data.iter().zip(&moods) {
+                params.push(&row.id);
+                params.push(&row.modified_date);
+                params.push(mood);
+            }
+            client.execute(statement, &params).await
+        })
+    }
+
+    fn modified_date(&self) -> NaiveDateTime {
+        self.modified_date
+    }
+
+    fn pkey(&self) -> i64 {
+        self.id
+    }
+}
+
+/// Binds the enum column as text and casts it to the postgres enum
+fn mood_upsert_query(table: &str, n: usize) -> String {
+    let values = (0..n).map(|i| format!("(${}, ${}, ${}::text::mood)", i * 3 + 1, i * 3 + 2, i * 3 + 3)).collect::<Vec<String>>().join(", ");
+    format!("INSERT INTO {} (id, modified_date, mood) VALUES {} ON CONFLICT (id) DO UPDATE SET modified_date = EXCLUDED.modified_date, mood = EXCLUDED.mood", table, values)
+}
+
+#[tokio::test]
+async fn test_custom_enum_column() {
+    let (_container, config) = start_postgres().await;
+    let client = connect(&config).await;
+    client.batch_execute("CREATE TYPE mood AS ENUM ('happy', 'sad'); CREATE TABLE test8 (id BIGINT PRIMARY KEY, modified_date TIMESTAMP NOT NULL, mood mood NOT NULL)").await.unwrap();
+
+    let mut quick_stream_builder = quick_stream_builder(config, "test8");
+    quick_stream_builder.queries(build_query_holder("test8", mood_upsert_query));
+    let upsert_quick_stream = quick_stream_builder.build_update();
+    let (tx, rx) = mpsc::channel::<Vec<MoodData>>(10);
+    let handle = tokio::spawn(async move {
+        upsert_quick_stream.run(rx).await.unwrap();
+    });
+
+    let data = (0..120).map(|id| MoodData {
+        id,
+        modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc(),
+        mood: if id % 2 == 0 { Mood::Happy } else { Mood::Sad },
+    }).collect();
+    tx.send(data).await.unwrap();
+    drop(tx);
+    handle.await.unwrap();
+
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test8 WHERE mood = 'happy'", 60).await, 60);
+    assert_eq!(wait_for_count(&client, "SELECT COUNT(*) FROM test8 WHERE mood = 'sad'", 60).await, 60);
+}
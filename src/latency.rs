@@ -0,0 +1,70 @@
+use std::{collections::HashMap, time::Duration};
+
+use hdrhistogram::Histogram;
+
+/// Latencies above an hour are recorded as an hour
+const MAX_LATENCY_MICROS: u64 = 3_600_000_000;
+
+/// Execute latencies of the batches of a tier, see `UpsertQuickStream::batch_latencies`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Histograms of the execute latencies of written batches per tier, recorded in microseconds
+#[derive(Debug, Default)]
+pub(crate) struct BatchLatencies {
+    histograms: HashMap<usize, Histogram<u64>>
+}
+
+impl BatchLatencies {
+    pub(crate) fn record(&mut self, tier: usize, duration: Duration) {
+        self.histograms
+            .entry(tier)
+            .or_insert_with(|| Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, 3).expect("latency histogram bounds are valid"))
+            .saturating_record(duration.as_micros() as u64);
+    }
+
+    pub(crate) fn snapshot(&self) -> HashMap<usize, LatencySnapshot> {
+        self.histograms.iter().map(|(tier, histogram)| (*tier, LatencySnapshot {
+            count: histogram.len(),
+            p50: Duration::from_micros(histogram.value_at_quantile(0.5)),
+            p90: Duration::from_micros(histogram.value_at_quantile(0.9)),
+            p99: Duration::from_micros(histogram.value_at_quantile(0.99)),
+            max: Duration::from_micros(histogram.max()),
+        })).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::BatchLatencies;
+
+    #[test]
+    fn test_batch_latencies() {
+        let mut batch_latencies = BatchLatencies::default();
+        for millis in 1..=100 {
+            batch_latencies.record(10, Duration::from_millis(millis));
+        }
+        batch_latencies.record(100, Duration::from_secs(7200));
+
+        let snapshot = batch_latencies.snapshot();
+        assert_eq!(snapshot.len(), 2);
+
+        let tens = snapshot[&10];
+        assert_eq!(tens.count, 100);
+        // recorded with 3 significant digits
+        assert!(tens.p50.as_micros().abs_diff(50_000) < 1_000);
+        assert!(tens.p99.as_micros().abs_diff(99_000) < 1_000);
+        assert!(tens.max.as_micros().abs_diff(100_000) < 1_000);
+
+        // saturated at the upper bound
+        assert!(snapshot[&100].max <= Duration::from_secs(3600) + Duration::from_secs(4));
+    }
+}
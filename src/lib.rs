@@ -1,15 +1,50 @@
 use std::{collections::HashSet, time::Duration};
 
 use log::debug;
+use clock::Clock;
 use upsert::Upsert;
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod builder;
+pub mod clock;
+pub mod error;
+#[cfg(feature = "latency-histogram")]
+pub mod latency;
+mod schema;
 pub mod upsert;
 
+#[cfg(all(test, feature = "integration-tests"))]
+mod integration_tests;
+
 fn remove_duplicates<T>(data: &mut Vec<T>) where T: Upsert<T> + Clone + Send + 'static {
     let mut hash_set = HashSet::new();
     data.sort_by(|x, y| y.modified_date().cmp(&x.modified_date()));
-    data.retain(|data| hash_set.insert(data.pkey().clone()))
+    data.retain(|data| hash_set.insert(data.conflict_key()))
+}
+
+/// Keeps the last record of every conflict key in the order they were produced, which leaves the table
+/// in the same state as writing every record one after the other
+fn remove_duplicates_keep_order<T>(data: &mut Vec<T>) where T: Upsert<T> + Clone + Send + 'static {
+    let mut hash_set = HashSet::new();
+    let mut keep = data.iter().rev().map(|data| hash_set.insert(data.conflict_key())).collect::<Vec<bool>>();
+    keep.reverse();
+    let mut keep = keep.into_iter();
+    data.retain(|_| keep.next().unwrap_or(true))
+}
+
+/// Groups the records by `Upsert::statement_variant`, in the order each variant first appears and keeping the order within a variant
+fn group_by_variant<T>(data: Vec<T>) -> Vec<Vec<T>> where T: Upsert<T> + Clone + Send + 'static {
+    let mut groups: Vec<(Option<String>, Vec<T>)> = vec![];
+    for record in data {
+        let variant = record.statement_variant().map(str::to_owned);
+        match groups.iter_mut().find(|(group_variant, _)| *group_variant == variant) {
+            Some((_, group)) => group.push(record),
+            None => groups.push((variant, vec![record])),
+        }
+    }
+
+    groups.into_iter().map(|(_, group)| group).collect()
 }
 
 fn split_vec_by_given<T>(mut data: Vec<T>, hundreds: usize, tens: usize, single_digit: usize) -> Vec<Vec<T>> where T: Upsert<T> + Clone + Send +'static {
@@ -27,14 +62,12 @@ fn split_vec_by_given<T>(mut data: Vec<T>, hundreds: usize, tens: usize, single_
         data = data_2.to_vec();
     }
 
-    if single_digit != data.len() {
-        panic!("Unreachable logic reached")
-    } else {
-        if !data.is_empty() {
-            results.push(data);
-        }
-        return results;
+    // the counts are derived from the length of the data by the callers
+    debug_assert_eq!(single_digit, data.len(), "single digit count does not match the records left");
+    if !data.is_empty() {
+        results.push(data);
     }
+    results
 }
 
 fn split_vec<T>(data: Vec<T>) -> Vec<Vec<T>> where T: Upsert<T> + Clone + Send + 'static {
@@ -48,9 +81,25 @@ fn split_vec<T>(data: Vec<T>) -> Vec<Vec<T>> where T: Upsert<T> + Clone + Send +
     split_vec_by_given(data, hundreds, tens, tens_remainder)
 }
 
-async fn introduce_lag(lag: u64) {
+/// Like `split_vec`, with at most one batch of 50 between the batches of a hundred and the batches of ten,
+/// e.g. 90 records are split into 5 batches (50 + 4 * 10) instead of 9
+fn split_vec_with_fifties<T>(mut data: Vec<T>) -> Vec<Vec<T>> where T: Upsert<T> + Clone + Send + 'static {
+    let mut remainder = data.split_off(data.len() / 100 * 100);
+    let mut results = split_vec(data);
+
+    if remainder.len() >= 50 {
+        let rest = remainder.split_off(50);
+        results.push(remainder);
+        remainder = rest;
+    }
+
+    results.append(&mut split_vec(remainder));
+    results
+}
+
+async fn introduce_lag(clock: &dyn Clock, lag: u64) {
     debug!("introducing lag: {}ms", lag);
-    tokio::time::sleep(Duration::from_millis(lag)).await;
+    clock.sleep(Duration::from_millis(lag)).await;
     debug!("introduced lag complete");
 }
 
@@ -133,7 +182,7 @@ mod tests {
 
         quick_stream_builder
             .cancellation_tocken(cancellation_token)
-            .max_connection_count(10)
+            .max_connection_count(20)
             .buffer_size(10)
             .single_digits(1)
             .tens(2)
@@ -149,7 +198,7 @@ mod tests {
 
         let (tx, rx) = mpsc::channel::<Vec<MockData>>(100);
         let handle = tokio::spawn(async move {
-            upsert_quick_stream.run(rx).await;
+            upsert_quick_stream.run(rx).await.unwrap();
         });
 
         let data = vec![
@@ -0,0 +1,102 @@
+use log::{info, trace};
+use tokio_postgres::Client;
+
+use crate::error::QuickStreamError;
+
+/// Columns a table is expected to have, see `QuickStreamBuilder::expected_columns`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TableSchema {
+    /// Table name, optionally qualified with its schema. Unqualified tables are looked up in the current schema.
+    pub(crate) table: String,
+    /// Column names paired with their type, either the `data_type` or the `udt_name` of `information_schema.columns`
+    pub(crate) columns: Vec<(String, String)>,
+}
+
+impl TableSchema {
+    fn schema_and_table(&self) -> (Option<&str>, &str) {
+        match self.table.split_once('.') {
+            Some((schema, table)) => (Some(schema), table),
+            None => (None, self.table.as_str()),
+        }
+    }
+
+    /// Describes every expected column which is missing or has another type than the actual columns of the table
+    fn mismatches(&self, actual_columns: &[(String, String, String)]) -> Vec<String> {
+        if actual_columns.is_empty() {
+            return vec![format!("table {} does not exist or has no columns", self.table)]
+        }
+
+        let mut mismatches = vec![];
+        for (column, type_) in &self.columns {
+            match actual_columns.iter().find(|(name, _, _)| name == column) {
+                Some((_, data_type, udt_name)) => {
+                    if !data_type.eq_ignore_ascii_case(type_) && !udt_name.eq_ignore_ascii_case(type_) {
+                        mismatches.push(format!("column {}.{} is {} ({}), expected {}", self.table, column, data_type, udt_name, type_));
+                    }
+                },
+                None => mismatches.push(format!("column {}.{} is missing", self.table, column)),
+            }
+        }
+        mismatches
+    }
+}
+
+/// Compares the expected columns of every table against `information_schema.columns`, failing with every mismatch found
+pub(crate) async fn verify_schema(name: &str, client: &Client, tables: &[TableSchema]) -> Result<(), QuickStreamError> {
+    let mut mismatches = vec![];
+    for table_schema in tables {
+        trace!("{}: verifying schema of table {}", name, table_schema.table);
+        let (schema, table) = table_schema.schema_and_table();
+        let rows = client.query(
+            "SELECT column_name::text, data_type::text, udt_name::text FROM information_schema.columns WHERE table_schema::text = COALESCE($1::text, current_schema()::text) AND table_name::text = $2::text",
+            &[&schema, &table]
+        ).await?;
+        let actual_columns = rows.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect::<Vec<(String, String, String)>>();
+        mismatches.append(&mut table_schema.mismatches(&actual_columns));
+    }
+
+    if mismatches.is_empty() {
+        info!("{}: schema of {} tables verified", name, tables.len());
+        Ok(())
+    } else {
+        Err(QuickStreamError::SchemaMismatch(mismatches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TableSchema;
+
+    fn actual_columns() -> Vec<(String, String, String)> {
+        vec![
+            ("id".to_string(), "bigint".to_string(), "int8".to_string()),
+            ("modified_date".to_string(), "timestamp without time zone".to_string(), "timestamp".to_string()),
+        ]
+    }
+
+    #[test]
+    fn test_matching_schema() {
+        let table_schema = TableSchema {
+            table: "public.test".to_string(),
+            columns: vec![("id".to_string(), "BIGINT".to_string()), ("modified_date".to_string(), "timestamp".to_string())],
+        };
+
+        assert_eq!(table_schema.schema_and_table(), (Some("public"), "test"));
+        assert!(table_schema.mismatches(&actual_columns()).is_empty());
+    }
+
+    #[test]
+    fn test_schema_mismatches() {
+        let table_schema = TableSchema {
+            table: "test".to_string(),
+            columns: vec![("id".to_string(), "text".to_string()), ("name".to_string(), "text".to_string())],
+        };
+
+        assert_eq!(table_schema.schema_and_table(), (None, "test"));
+        assert_eq!(table_schema.mismatches(&actual_columns()), vec![
+            "column test.id is bigint (int8), expected text".to_string(),
+            "column test.name is missing".to_string(),
+        ]);
+        assert_eq!(table_schema.mismatches(&[]), vec!["table test does not exist or has no columns".to_string()]);
+    }
+}
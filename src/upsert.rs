@@ -1,31 +1,558 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{hash_map::RandomState, BTreeMap, HashMap, VecDeque}, hash::{BuildHasher, Hasher}, future::Future, sync::{atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering}, Arc, Mutex, MutexGuard, PoisonError, RwLock}, time::{Duration, Instant}};
 
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
-use futures::future::BoxFuture;
-use log::{error, info, trace, warn};
+use futures::{future::BoxFuture, FutureExt};
+use log::{error, info, log, trace, warn, Level};
 use native_tls::{Certificate, TlsConnector};
 use postgres_native_tls::MakeTlsConnector;
-use tokio::{sync::mpsc::{self, Receiver, Sender}, task::JoinHandle};
-use tokio_postgres::{Client, Error, NoTls, Statement};
+use tokio::{runtime::Handle, sync::{mpsc::{self, Receiver, Sender}, oneshot, Notify, Semaphore}, task::JoinHandle};
+use tokio_postgres::{error::SqlState, Client, Error, NoTls, Row, Statement};
 use tokio_util::sync::CancellationToken;
 
-use crate::{builder::support::QueryHolder, introduce_lag, remove_duplicates, split_vec};
+#[cfg(feature = "latency-histogram")]
+use crate::latency::{BatchLatencies, LatencySnapshot};
+use crate::{builder::support::QueryHolder, clock::{Clock, TokioClock}, error::QuickStreamError, group_by_variant, introduce_lag, remove_duplicates, remove_duplicates_keep_order, schema::{self, TableSchema}, split_vec, split_vec_with_fifties};
 
+/**
+ Writes a batch of records with the prepared statement of its tier. Parameters are bound by the implementation, in the order of the query.
+ * Columns of user defined types, e.g. enums or composites, are bound with a `ToSql` implementation accepting that type,
+   such as `#[derive(ToSql)]` of `postgres-types` with its `derive` feature. The type oids are looked up by `tokio_postgres` while preparing the statement.
+ * Alternatively bind such a column as text and cast it in the query, e.g. `$1::text::mood`
+ * Duplicates are removed by `conflict_key` before records are split into batches, so a batch never holds the same `conflict_key` twice.
+   `conflict_key` has to identify the `ON CONFLICT` target of the queries, otherwise postgres rejects batches with
+   "ON CONFLICT DO UPDATE command cannot affect row a second time", see `QuickStreamError::DuplicateConflictTarget`
+ * `conflict_key` defaults to `pkey`. Override it when the `ON CONFLICT` target is another unique key than `pkey`, e.g. a natural key next to a surrogate `pkey`
+ */
 #[async_trait]
 pub trait Upsert<T>: Send + Sync
 where
     T: Clone + Send + Sync,
 {
-    fn upsert(
-        client: &Client,
+    fn upsert<'a>(
+        client: &'a Client,
         data: Vec<T>,
-        statement: &Statement,
+        statement: &'a Statement,
         thread_id: i64,
-    ) -> BoxFuture<'static, Result<u64, Error>>;
+    ) -> BoxFuture<'a, Result<u64, Error>>;
+
+    /**
+     Like `upsert`, returning the rows of the `RETURNING` clause of the query, e.g. executing the statement with `client.query`.
+     Required with `QuickStreamBuilder::returning` along with `RETURNING` set to `true`, `run` fails with `ReturningUnsupported` otherwise.
+     * Defaults to `upsert`, returning no rows
+     */
+    fn upsert_returning<'a>(
+        client: &'a Client,
+        data: Vec<T>,
+        statement: &'a Statement,
+        thread_id: i64,
+    ) -> BoxFuture<'a, Result<Vec<Row>, Error>> {
+        let upserted = Self::upsert(client, data, statement, thread_id);
+        Box::pin(async move { upserted.await.map(|_| vec![]) })
+    }
+
+    /// Whether `upsert_returning` is implemented, required with `QuickStreamBuilder::returning`
+    const RETURNING: bool = false;
 
     fn modified_date(&self) -> NaiveDateTime;
     fn pkey(&self) -> i64;
+
+    /// Key of the `ON CONFLICT` target, used to remove duplicates. Defaults to `pkey`
+    fn conflict_key(&self) -> i64 {
+        self.pkey()
+    }
+
+    /// Variant of `QuickStreamBuilder::variant_queries` whose statement writes the record. Defaults to `None`, writing it with `queries`
+    fn statement_variant(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Why the senders (database connections) of a tier were changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebalanceReason {
+    /// The sender with the highest capacity was at or below the connection creation threshold
+    CapacityBelowThreshold,
+    /// Senders whose data ingestor stopped were removed
+    ClosedSenders,
+    /// Senders above the initial amount of the tier were idle and removed
+    IdleSenders,
+    /// Senders were created to restore the initial amount of the tier, after data ingestors stopped or panicked
+    BelowTierMinimum,
+    /// Senders whose data ingestor made no progress within the stalled ingestor threshold were removed
+    StalledSenders,
+}
+
+/// A change to the senders (database connections) of a tier, passed to the hook set with `QuickStreamBuilder::on_rebalance`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebalanceEvent {
+    pub tier: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub reason: RebalanceReason,
+}
+
+pub type RebalanceHook = Arc<dyn Fn(RebalanceEvent) + Send + Sync>;
+
+/// A batch handed to a sender, before it is written, passed to the hook set with `QuickStreamBuilder::on_dispatch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DispatchEvent {
+    pub tier: usize,
+    pub sender_id: i64,
+    pub batch_size: usize,
+}
+
+pub type DispatchHook = Arc<dyn Fn(DispatchEvent) + Send + Sync>;
+
+/// A written or failed batch, passed in the order batches were dispatched to the hook set with `QuickStreamBuilder::on_ordered_confirmation`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConfirmation {
+    /// Position of the batch in the order of dispatching, starting at 0 without gaps
+    pub sequence: u64,
+    pub rows: usize,
+    /// Whether the batch was written. `false` when it failed or its data ingestor stopped before writing it, its rows are then not written
+    pub written: bool,
+}
+
+pub type ConfirmationHook = Arc<dyn Fn(BatchConfirmation) + Send + Sync>;
+
+/**
+ Numbers the batches as they are dispatched and holds back the confirmations of settled batches until every earlier batch is settled.
+ A batch is settled once it is written or failed. Releases the flushes once every batch dispatched before them is settled.
+ * Holds one entry, the sequence, the row count and whether it was written, per batch settled ahead of the oldest batch not settled yet. No records are held.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct ConfirmationSequencer {
+    dispatched: u64,
+    next: u64,
+    settled: BTreeMap<u64, (usize, bool)>,
+    /// Failed batches confirmed since the barrier of the last flush was reached
+    failed: u64,
+    /// Flushes requested but not yet seen by the main channel receiver, with the sender awaited by `UpsertQuickStream::flush`
+    requested_flushes: Vec<Option<oneshot::Sender<u64>>>,
+    /// Flushes waiting for every batch dispatched before their barrier to be settled
+    flushes: VecDeque<(u64, Option<oneshot::Sender<u64>>)>,
+}
+
+impl ConfirmationSequencer {
+    fn next_sequence(&mut self) -> u64 {
+        let sequence = self.dispatched;
+        self.dispatched += 1;
+        sequence
+    }
+
+    fn request_flush(&mut self, flushed: Option<oneshot::Sender<u64>>) {
+        self.requested_flushes.push(flushed);
+    }
+
+    /// Sets the barrier of every requested flush at the batches dispatched so far
+    fn place_flush_barriers(&mut self) {
+        let barrier = self.dispatched;
+        let requested_flushes = self.requested_flushes.drain(..).map(|flushed| (barrier, flushed)).collect::<Vec<_>>();
+        self.flushes.extend(requested_flushes);
+        self.release_flushes();
+    }
+
+    /// Passes the failed batches to every flush whose batches are all settled
+    fn release_flushes(&mut self) {
+        while self.flushes.front().is_some_and(|(barrier, _)| *barrier <= self.next) {
+            if let Some((_, Some(flushed))) = self.flushes.pop_front() {
+                // the flush is no longer awaited
+                let _ = flushed.send(self.failed);
+            }
+            self.failed = 0;
+        }
+    }
+
+    /// Records a settled batch, returning the batches which are now confirmed in order.
+    /// A batch settled twice keeps its first outcome, e.g. when it was failed with its data ingestor while being moved to another sender
+    fn confirm(&mut self, sequence: u64, rows: usize, written: bool) -> Vec<BatchConfirmation> {
+        if sequence >= self.next {
+            self.settled.entry(sequence).or_insert((rows, written));
+        }
+        let mut confirmed = vec![];
+        while let Some((rows, written)) = self.settled.remove(&self.next) {
+            // a barrier at this batch covers only the batches before it
+            self.release_flushes();
+            if !written {
+                self.failed += 1;
+            }
+            confirmed.push(BatchConfirmation { sequence: self.next, rows, written });
+            self.next += 1;
+        }
+        self.release_flushes();
+        confirmed
+    }
+
+    /// Batches settled but held back, as an earlier batch is not settled yet
+    fn held_back(&self) -> usize {
+        self.settled.len()
+    }
+}
+
+/// Which queries the data ingestors execute, see `QuickStreamBuilder::do_nothing_queries`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictMode {
+    /// Executes `queries`, overwriting existing rows
+    DoUpdate,
+    /// Executes `do_nothing_queries`, skipping existing rows
+    DoNothing,
+}
+
+impl From<ConflictMode> for bool {
+    /// Whether the mode is `DoNothing`, as it is stored in an `AtomicBool` shared between the data ingestors
+    fn from(conflict_mode: ConflictMode) -> Self {
+        conflict_mode == ConflictMode::DoNothing
+    }
+}
+
+/**
+ How the data ingestors execute a batch, see `QuickStreamBuilder::execution_mode`
+ * `MultiRow` suits tables whose rows always bind the same number of parameters, every tier writes its batch with one statement
+ * `SingleRowPipelined` suits tables whose rows can not be written with a fixed number of parameters per row,
+   e.g. wide tables close to the parameter limit of postgres or `Upsert::upsert` implementations binding a variable number of columns
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecutionMode {
+    /// Executes the multi row statement of the tier, one statement per batch
+    #[default]
+    MultiRow,
+    /**
+     Executes the statement of the single digit tier 1 once per record. The executes of a batch are issued together
+     on the connection of the data ingestor, which pipelines them instead of waiting for every round trip.
+     * Slower than `MultiRow`, as postgres still executes one statement per record
+     * A batch is not atomic, when one of its records fails the others may have been written
+     */
+    SingleRowPipelined,
+}
+
+/// How a batch picks its sender among the senders of its tier, see `QuickStreamBuilder::sender_selection`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SenderSelection {
+    /// The sender with the most free capacity, which under even load keeps picking the same few senders
+    #[default]
+    HighestCapacity,
+    /// A random sender above the connection creation threshold, weighted by its free capacity, spreading the load over every sender
+    WeightedRandom,
+}
+
+/// Picks an index with a probability proportional to its weight, from a random number. `None` when every weight is 0.
+fn weighted_random_index(weights: &[usize], random: u64) -> Option<usize> {
+    let total = weights.iter().sum::<usize>();
+    if total == 0 {
+        return None
+    }
+
+    let mut target = (random % total as u64) as usize;
+    weights.iter().position(|weight| {
+        if target < *weight {
+            return true
+        }
+        target -= weight;
+        false
+    })
+}
+
+/// A random number from the randomly seeded hasher of the standard library, random enough to spread batches without a dependency
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Subtracts at most what the counter holds, returning the amount subtracted
+fn take_saturating(counter: &AtomicUsize, amount: usize) -> usize {
+    let held = counter.fetch_update(Ordering::AcqRel, Ordering::Acquire, |held| Some(held.saturating_sub(amount))).unwrap_or_else(|held| held);
+    held.min(amount)
+}
+
+/// The next number of the fixed splitmix64 sequence advanced through the given state
+fn splitmix64(state: &AtomicU64) -> u64 {
+    let mut z = state.fetch_add(0x9E3779B97F4A7C15, Ordering::Relaxed).wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// A connected data ingestor with the statements of its tier
+struct PreparedIngestor {
+    query: String,
+    client: Client,
+    statement: Statement,
+    do_nothing_statement: Option<Statement>,
+    dual_write_statement: Option<Statement>,
+    /// Statements of `variant_queries` by their variant
+    variant_statements: HashMap<String, Statement>,
+    /// Completes as soon as the task holding the connection stops
+    closed: oneshot::Receiver<()>,
+    connected_at: Instant
+}
+
+/**
+ A parameter changed while the stream runs, see `UpsertQuickStream::tune`.
+ Every other parameter, e.g. `buffer_size` or the initial amount of senders of the tiers, requires building the stream again.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tuning {
+    /// Raising it lets the tiers scale up further. Lowering it stops creating senders and removes idle senders until the count is below it,
+    /// senders with data in their channel are never removed
+    MaxConnectionCount(usize),
+    /// Capacity percentage of the channel of a sender at or below which another sender is created, from 0 to 100
+    ConnectionCreationThreshold(f64),
+}
+
+/// Limits how often something happens, allowing bursts up to its capacity and refilling continuously
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_second: f64,
+    refilled_at: Instant
+}
+
+impl TokenBucket {
+    pub(crate) fn new(capacity: u32, per: Duration, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refill_per_second: capacity as f64 / per.as_secs_f64(),
+            refilled_at: now
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.refilled_at = now;
+
+        if self.tokens >= 1f64 {
+            self.tokens -= 1f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Uses up the allowance, which then refills from `now` on
+    fn drain(&mut self, now: Instant) {
+        self.tokens = 0f64;
+        self.refilled_at = now;
+    }
+}
+
+/// Where a stream is in its lifecycle, see `UpsertQuickStream::state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    /// `run` was not called yet
+    Idle,
+    /// Testing the connection, verifying the schema and creating the initial senders
+    Starting,
+    /// Receiving from the main channel
+    Running,
+    /// Stopped receiving from the main channel, the data ingestors are writing what is left in their channels
+    Draining,
+    /// Every data ingestor stopped, or the stream failed to start
+    Stopped,
+}
+
+impl StreamState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => StreamState::Idle,
+            1 => StreamState::Starting,
+            2 => StreamState::Running,
+            3 => StreamState::Draining,
+            _ => StreamState::Stopped,
+        }
+    }
+
+    pub(crate) fn as_u8(self) -> u8 {
+        match self {
+            StreamState::Idle => 0,
+            StreamState::Starting => 1,
+            StreamState::Running => 2,
+            StreamState::Draining => 3,
+            StreamState::Stopped => 4,
+        }
+    }
+}
+
+/// How `UpsertQuickStream::run_supervised` restarts a failed stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Restarts before giving up, counted over the whole lifetime of `run_supervised`
+    pub max_restarts: usize,
+    /// Wait before the first restart, doubled for every further restart
+    pub backoff: Duration,
+    /// Upper bound of the doubled backoff
+    pub max_backoff: Duration,
+}
+
+impl RestartPolicy {
+    /// Wait before the restart following the given amount of restarts
+    fn backoff(&self, restarts: usize) -> Duration {
+        self.backoff.saturating_mul(2u32.saturating_pow(restarts.min(u32::MAX as usize) as u32)).min(self.max_backoff)
+    }
+}
+
+impl Default for RestartPolicy {
+    /// Restarts up to 5 times, waiting 1s, 2s, 4s, 8s and 16s
+    fn default() -> Self {
+        Self { max_restarts: 5, backoff: Duration::from_secs(1), max_backoff: Duration::from_secs(60) }
+    }
+}
+
+/// Window over which `UpsertQuickStream::connection_creation_rate` is averaged
+const SCALE_UP_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Default pause of scaling up after the database refused a connection, see `QuickStreamBuilder::too_many_connections_cooldown`
+pub(crate) const TOO_MANY_CONNECTIONS_COOLDOWN: Duration = Duration::from_secs(30);
+/// Default retries of a batch failing as the database is read only, see `QuickStreamBuilder::read_only_max_retries`
+pub(crate) const READ_ONLY_MAX_RETRIES: usize = 10;
+/// Least time a sender created to scale up gets to connect, see `UpsertQuickStream::poll_connecting_senders`
+const SCALE_UP_READINESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a stream ingested by the time `run` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Rows accepted for ingestion after removing duplicates, see `UpsertQuickStream::total_rows`
+    pub rows_accepted: u64,
+    /// Rows of the batches written successfully. Below `rows_accepted` while data ingestors are still writing or when batches failed
+    pub rows_written: u64,
+    /// Database connections of the data ingestors when the main channel receiver stopped
+    pub connections: usize,
+    /// Whether every data ingestor drained its channel before `run` returned, which `run` waits for on cancellation only
+    pub drained: bool,
+}
+
+/// A sender (database connection) of a tier, see `UpsertQuickStream::sender_topology`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SenderSnapshot {
+    pub id: i64,
+    /// Free capacity of its channel, in percent of the buffer size
+    pub capacity: f64,
+    pub in_flight_records: usize,
+    /// Milliseconds since it last wrote a batch, or since it was created, while nothing is in flight
+    pub idle_millis: Option<u64>,
+    /// Error its data ingestor stopped with, until the sender is removed
+    pub last_error: Option<String>,
+}
+
+/// The senders of a tier, see `UpsertQuickStream::sender_topology`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TierSnapshot {
+    pub tier: usize,
+    pub senders: Vec<SenderSnapshot>,
+}
+
+/// Every sender of the stream by tier, the machine readable counterpart of the sender status log, see `UpsertQuickStream::sender_topology`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SenderTopology {
+    pub name: String,
+    pub total_senders: usize,
+    pub max_connection_count: usize,
+    pub tiers: Vec<TierSnapshot>,
+}
+
+/// Handle of a stream spawned by `UpsertQuickStream::start`
+#[derive(Debug)]
+pub struct StreamHandle {
+    name: String,
+    join_handle: JoinHandle<Result<ShutdownReport, QuickStreamError>>,
+    completed: oneshot::Receiver<ShutdownReport>
+}
+
+impl StreamHandle {
+    /// Waits until the main channel receiver of the stream stopped, returning the report or the error of `run`.
+    /// Panics if `run` panicked.
+    pub async fn join(self) -> Result<ShutdownReport, QuickStreamError> {
+        Self::join_task(&self.name, self.join_handle).await
+    }
+
+    async fn join_task(name: &str, join_handle: JoinHandle<Result<ShutdownReport, QuickStreamError>>) -> Result<ShutdownReport, QuickStreamError> {
+        match join_handle.await {
+            Ok(result) => result,
+            Err(error) => panic!("{}: stream task failed : {}", name, error),
+        }
+    }
+
+    /**
+     Waits until the main channel receiver stopped and every data ingestor drained its channel, so every batch pushed to them
+     is written or failed. A supervisor can await it before exiting the process.
+     * Returns the report of the drained stream or the error of `run`. Panics if `run` panicked.
+     */
+    pub async fn completed(self) -> Result<ShutdownReport, QuickStreamError> {
+        let shutdown_report = Self::join_task(&self.name, self.join_handle).await?;
+        match self.completed.await {
+            Ok(shutdown_report) => Ok(shutdown_report),
+            // nothing was left to drain, e.g. in strict fifo
+            Err(_) => Ok(shutdown_report),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.join_handle.is_finished()
+    }
+}
+
+/// Warns when `run` is dropped before its main channel receiver stopped, e.g. when its task is aborted,
+/// as the payload buffered in the lag cycle is lost and the data ingestors stop with it
+struct RunGuard<'a> {
+    name: &'a str,
+    state: &'a AtomicU8,
+    finished: bool
+}
+
+impl RunGuard<'_> {
+    /// Marks the stream as stopped by an error instead of dropped, `run` returns the error and stops the stream itself
+    fn fail(&mut self, error: QuickStreamError) -> QuickStreamError {
+        self.finished = true;
+        error
+    }
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            warn!("{}: stream dropped while running, buffered data may be lost. close the main channel to drain the stream instead", self.name);
+            self.state.store(StreamState::Stopped.as_u8(), Ordering::Release);
+        }
+    }
+}
+
+/// A batch dispatched to a data ingestor, until it is written or failed
+#[derive(Debug, Clone)]
+struct PushedBatch {
+    sequence: u64,
+    rows: usize,
+    /// Spans the batch from its dispatch until it is settled, a child of the span of its payload
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// Records pushed to a data ingestor which are not written yet, shared between the sender and its data ingestor.
+#[derive(Debug, Default)]
+struct InFlight {
+    records: AtomicUsize,
+    /// Permits of `max_in_flight_records` taken for the records, given back with them
+    permits: AtomicUsize,
+    released: Notify,
+    /// Batches pushed to the data ingestor, in the order they were pushed
+    batches: Mutex<VecDeque<PushedBatch>>,
+    /// Last time the data ingestor wrote a batch, or received one while it had nothing in flight
+    progressed_at: Mutex<Option<Instant>>,
+    /// Whether the data ingestor was counted as stalled since it last progressed
+    stalled: AtomicBool,
+    /// Error the data ingestor stopped with
+    last_error: Mutex<Option<String>>
+}
+
+impl InFlight {
+    fn progress(&self, now: Instant) {
+        *self.progressed_at.lock().unwrap() = Some(now);
+        self.stalled.store(false, Ordering::Release);
+    }
 }
 
 #[derive(Debug)]
@@ -33,16 +560,22 @@ struct UpsertData<T> where T: Upsert<T> + Clone + Send {
     pub tx: Sender<Vec<T>>,
     pub join_handler: JoinHandle<u8>,
     pub id: i64,
-    pub type_: usize
+    pub type_: usize,
+    pub in_flight: Arc<InFlight>,
+    pub created_at: Instant,
+    pub ready: Option<oneshot::Receiver<Result<(), QuickStreamError>>>
 }
 
 impl<T> UpsertData<T> where T: Upsert<T> + Clone + Send {
-    pub fn new(tx: Sender<Vec<T>>, join_handler: JoinHandle<u8>, id: i64, type_: usize) -> Self {
+    pub fn new(tx: Sender<Vec<T>>, join_handler: JoinHandle<u8>, id: i64, type_: usize, in_flight: Arc<InFlight>, created_at: Instant) -> Self {
         Self {
             tx,
             join_handler,
             id,
-            type_
+            type_,
+            in_flight,
+            created_at,
+            ready: None
         }
     }
 }
@@ -50,60 +583,244 @@ impl<T> UpsertData<T> where T: Upsert<T> + Clone + Send {
 
 
 #[derive(Default, Clone)]
-#[allow(dead_code)] //for cancellation token, TODO remove when used
 pub struct UpsertQuickStream {
     pub(crate) cancellation_token: CancellationToken,
-    pub(crate) max_con_count: usize,
+    pub(crate) max_con_count: Arc<AtomicUsize>,
     pub(crate) buffer_size: usize,
     pub(crate) single_digits: usize,
     pub(crate) tens: usize,
     pub(crate) hundreds: usize,
+    pub(crate) fifties: Option<usize>,
     pub(crate) db_config: tokio_postgres::Config,
-    pub(crate) tls: Option<Certificate>,
+    pub(crate) tls: Arc<RwLock<Option<Certificate>>>,
     pub(crate) queries: QueryHolder,
+    pub(crate) do_nothing_queries: Option<QueryHolder>,
+    pub(crate) dual_write_queries: Option<QueryHolder>,
+    pub(crate) variant_queries: HashMap<String, QueryHolder>,
+    pub(crate) returning: Option<Sender<Vec<Row>>>,
+    pub(crate) conflict_mode: Arc<AtomicBool>,
     pub(crate) max_records_per_cycle_batch: usize, //a batch = introduced_lag_cycles
     pub(crate) introduced_lag_cycles: usize,
     pub(crate) introduced_lag_in_millies: u64,
-    pub(crate) connection_creation_threshold: f64,
+    /// `f64` bits, as there is no atomic float
+    pub(crate) connection_creation_threshold: Arc<AtomicU64>,
+    pub(crate) pending_tunings: Arc<Mutex<Vec<Tuning>>>,
     pub(crate) name: String,
-    pub(crate) print_con_config: bool
+    pub(crate) print_con_config: bool,
+    pub(crate) status_log_level: Option<Level>,
+    pub(crate) slow_batch_threshold: Option<Duration>,
+    pub(crate) read_only_reconnect_delay: Option<Duration>,
+    pub(crate) read_only_max_retries: usize,
+    pub(crate) commit_batch_size: Option<usize>,
+    pub(crate) slow_batch_count: Arc<AtomicU64>,
+    pub(crate) stalled_ingestor_threshold: Option<Duration>,
+    pub(crate) recreate_stalled_ingestors: bool,
+    pub(crate) stalled_ingestor_count: Arc<AtomicU64>,
+    pub(crate) dry_run: bool,
+    pub(crate) max_in_flight_records_per_connection: Option<usize>,
+    pub(crate) in_flight_records: Arc<AtomicUsize>,
+    pub(crate) max_in_flight_records: Option<usize>,
+    /// Permits of `max_in_flight_records`, one per record in flight
+    pub(crate) in_flight_permits: Option<Arc<Semaphore>>,
+    pub(crate) sender_topology: Arc<Mutex<Option<SenderTopology>>>,
+    pub(crate) session_variable: Option<String>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) max_connection_lifetime: Option<Duration>,
+    pub(crate) max_total_rows: Option<u64>,
+    pub(crate) total_rows: Arc<AtomicU64>,
+    pub(crate) written_rows: Arc<AtomicU64>,
+    pub(crate) rebalance_hook: Option<RebalanceHook>,
+    pub(crate) dispatch_hook: Option<DispatchHook>,
+    pub(crate) confirmation_hook: Option<ConfirmationHook>,
+    pub(crate) confirmation_sequencer: Arc<Mutex<ConfirmationSequencer>>,
+    pub(crate) flush_requested: Arc<Notify>,
+    pub(crate) state: Arc<AtomicU8>,
+    pub(crate) scale_up_hysteresis: Option<(f64, Duration)>,
+    pub(crate) last_scale_up: Arc<Mutex<Option<Instant>>>,
+    pub(crate) connection_removal_threshold: Option<f64>,
+    pub(crate) connection_removal_cooldown: Option<Duration>,
+    pub(crate) connection_creation_limiter: Option<Arc<Mutex<TokenBucket>>>,
+    pub(crate) too_many_connections_cooldown: Duration,
+    pub(crate) scale_up_paused_until: Arc<Mutex<Option<Instant>>>,
+    pub(crate) scale_ups: Arc<Mutex<VecDeque<Instant>>>,
+    pub(crate) strict_readiness: bool,
+    pub(crate) skip_connectivity_test: bool,
+    pub(crate) adaptive_lag_cycles: bool,
+    pub(crate) deterministic: bool,
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+    /// State of the fixed sequence of numbers drawn while `deterministic`
+    pub(crate) random_state: Arc<AtomicU64>,
+    pub(crate) flush_below_records: Option<usize>,
+    pub(crate) watermark_query: Option<(String, Duration)>,
+    pub(crate) written_watermark: Arc<Mutex<Option<NaiveDateTime>>>,
+    pub(crate) watermark_frozen: Arc<AtomicBool>,
+    pub(crate) runtime_handle: Option<Handle>,
+    pub(crate) max_payload_records: Option<usize>,
+    pub(crate) expected_columns: Option<Vec<TableSchema>>,
+    pub(crate) strict_fifo: bool,
+    pub(crate) execution_mode: ExecutionMode,
+    pub(crate) sender_selection: SenderSelection,
+    #[cfg(feature = "latency-histogram")]
+    pub(crate) batch_latencies: Arc<Mutex<BatchLatencies>>
 }
 
 #[allow(dead_code)]
 impl UpsertQuickStream {
-    pub async fn run<T>(&self, mut rx: Receiver<Vec<T>>) where T: Upsert<T> + Clone + Send + 'static {
+    /**
+     Receives payloads from the main channel and pushes them to the data ingestors until the main channel is closed or the cancellation token is cancelled.
+     * On shutdown senders are not rebalanced. They are all dropped at once, idle data ingestors stop right away
+       and the others write what is left in their channels in parallel, in the batches they were pushed with, before stopping.
+     * When the main channel is closed `run` returns without waiting for the data ingestors.
+       When cancelled, it stops receiving from the main channel and returns once every data ingestor drained its channel
+     * Cancellation never interrupts a batch being written, every upsert which started is awaited to its result before `run` returns.
+       Payloads still queued in the main channel are left unwritten, as are the batches queued to a data ingestor which stopped on an error
+     */
+    pub async fn run<T>(&self, rx: Receiver<Vec<T>>) -> Result<ShutdownReport, QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        let mut rx = rx;
+        self.run_with_completion(&mut rx, None).await
+    }
+
+    /// Runs the stream, sending the report through `completed` once every data ingestor drained, see `StreamHandle::completed`
+    async fn run_with_completion<T>(&self, rx: &mut Receiver<Vec<T>>, completed: Option<oneshot::Sender<ShutdownReport>>) -> Result<ShutdownReport, QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        self.set_state(StreamState::Starting);
+        let result = self.run_stream(rx, completed).await;
+        if result.is_err() {
+            self.set_state(StreamState::Stopped);
+        }
+        result
+    }
+
+    async fn run_stream<T>(&self, rx: &mut Receiver<Vec<T>>, completed: Option<oneshot::Sender<ShutdownReport>>) -> Result<ShutdownReport, QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
 
         info!("{}: upsert quick stream is starting", self.name);
-        info!("{}: testing database connections", self.name);
-        let _client = self.get_db_client().await;
-        drop(_client);
-        info!("{}: database sucsessfully connected", self.name);
+        self.verify_returning::<T>()?;
+        if self.skip_connectivity_test {
+            info!("{}: skipping database connectivity test", self.name);
+        } else {
+            info!("{}: testing database connections", self.name);
+            let _client = self.get_db_client().await?;
+            drop(_client);
+            info!("{}: database sucsessfully connected", self.name);
+        }
+
+        if let Some(expected_columns) = &self.expected_columns {
+            info!("{}: verifying schema", self.name);
+            let client = self.get_db_client().await?;
+            schema::verify_schema(&self.name, &client, expected_columns).await?;
+        }
+
+        if self.strict_fifo {
+            self.set_state(StreamState::Running);
+            let shutdown_report = self.run_fifo(rx).await?;
+            self.set_state(StreamState::Stopped);
+            return Ok(shutdown_report)
+        }
         let mut tx_count = 0;
 
         trace!("{}: initiating senders", self.name);
         let mut senders = self.init_senders::<T>(&mut tx_count);
         trace!("{}: inititating senders complete", self.name);
+
+        if self.strict_readiness {
+            info!("{}: waiting for all data ingestors to prepare their statements", self.name);
+            self.wait_for_readiness(&mut senders).await?;
+            info!("{}: all data ingestors are ready", self.name);
+        }
         
+        // stops the watermark writer when run returns
+        let watermark_cancellation_token = self.cancellation_token.child_token();
+        let _watermark_guard = watermark_cancellation_token.clone().drop_guard();
+        if let Some((watermark_query, watermark_interval)) = self.watermark_query.to_owned() {
+            info!("{}: starting watermark writer", self.name);
+            let self_clone = self.to_owned();
+            self.spawn(async move {
+                if let Err(error) = self_clone.write_watermarks(watermark_query, watermark_interval, watermark_cancellation_token).await {
+                    error!("{}: watermark writer stopped with error : {}", self_clone.name, error);
+                }
+            });
+        }
+
         info!("{}: main channel receiver starting", self.name);
-        while let Some(mut data) = rx.recv().await {
-            if data.len() >= self.max_records_per_cycle_batch {
-                trace!("{}: data count: {} exceeds max records per cycle batch: {}. proceesing for ingestion", self.name, data.len(), self.max_records_per_cycle_batch);
+        let mut run_guard = RunGuard { name: &self.name, state: &self.state, finished: false };
+        self.set_state(StreamState::Running);
+        let mut quota_reached;
+        let mut cancelled = false;
+        loop {
+            // a flush pushes the payloads queued before it regardless of the batch thresholds and the lag cycles
+            let (mut data, mut flushing) = tokio::select! {
+                data = rx.recv() => match data {
+                    Some(data) => (data, false),
+                    None => break,
+                },
+                _ = self.flush_requested.notified() => (self.take_queued(rx), true),
+                _ = self.cancellation_token.cancelled() => {
+                    info!("{}: cancellation received, main channel receiver stopping", self.name);
+                    cancelled = true;
+                    break
+                },
+            };
+
+            self.apply_tunings();
 
+            // removing duplicates keeps at least one record per primary key, so only an empty payload has nothing to write
+            if data.is_empty() {
+                trace!("{}: empty payload received, skipping", self.name);
+                if flushing {
+                    self.place_flush_barriers();
+                }
+                continue;
+            }
+
+            // duplicates are removed before anything is counted or split, so the tiers are chosen by the amount of
+            // distinct primary keys and a batch never carries the same primary key twice
+            quota_reached = false;
+            if let Some(max_payload_records) = self.max_payload_records.filter(|max_payload_records| data.len() > *max_payload_records) {
+                let (chunks, remainder) = self.chunk_payload(data, max_payload_records);
+                let mut chunks = chunks.into_iter();
+                for mut chunk in chunks.by_ref() {
+                    quota_reached = self.take_quota(&mut chunk);
+                    self.push_to_handle(&mut senders, self.split_by_variant(chunk), &mut tx_count).await.map_err(|error| run_guard.fail(error))?;
+                    if quota_reached {
+                        break;
+                    }
+                }
+                data = remainder;
+                if quota_reached {
+                    let discarded = chunks.map(|chunk| chunk.len()).sum::<usize>() + data.len();
+                    if discarded > 0 {
+                        warn!("{}: max total rows {} reached, discarding {} rows", self.name, self.total_rows(), discarded);
+                    }
+                    data.clear();
+                }
+            } else {
                 trace!("{}: removing duplicates", self.name);
                 remove_duplicates(&mut data);
                 trace!("{}: removing duplicates complete", self.name);
+            }
+
+            if quota_reached {
+                // nothing is left to write, the lag cycles would only receive payloads to discard
+                trace!("{}: max total rows reached, skipping the remainder of the payload", self.name);
+            } else if data.len() >= self.max_records_per_cycle_batch {
+                trace!("{}: data count: {} exceeds max records per cycle batch: {}. proceesing for ingestion", self.name, data.len(), self.max_records_per_cycle_batch);
+
+                quota_reached = self.take_quota(&mut data);
 
                 trace!("{}: splitting vectors for batch ingestion", self.name);
-                let vec_data = split_vec(data);
+                let vec_data = self.split_by_variant(data);
                 trace!("{}: splitting vectors complete. batch count: {}", self.name, vec_data.len());
 
                 trace!("{}: data ingestion starting for batches", self.name);
-                self.push_to_handle(&mut senders, vec_data.to_owned(), &mut tx_count).await;
+                self.push_to_handle(&mut senders, vec_data.to_owned(), &mut tx_count).await.map_err(|error| run_guard.fail(error))?;
                 trace!("{}: data pushed for ingestion", self.name);
             } else {
                 trace!(target: format!("").as_str() ,"{}: data count: {} does not exceeds max records per cycle batch: {}", self.name, data.len(), self.max_records_per_cycle_batch);
 
                 trace!("{}: starting lag cycles", self.name);
+                let max_lag_cycles = match self.adaptive_lag_cycles && !self.deterministic {
+                    true => self.adapt_lag_cycles(self.sender_saturation(&senders)),
+                    false => self.introduced_lag_cycles,
+                };
                 let mut introduced_lag_cycles = 0;
                 'inner: loop {
                     match rx.try_recv() {
@@ -126,466 +843,3205 @@ impl UpsertQuickStream {
 
                             trace!("{}: lag cycles: {}", self.name, introduced_lag_cycles);
                             // greater than or equal is used allowing 0 lag cycles
-                            if introduced_lag_cycles >= self.introduced_lag_cycles {
-                                trace!("{}: lag cycles: {} exceeds or reached max introduced lag cycles. data count : {}. proceeding for ingestion.", self.name, self.introduced_lag_cycles, data.len());
+                            if introduced_lag_cycles >= max_lag_cycles || flushing || self.flushes_below_records(introduced_lag_cycles, data.len()) {
+                                trace!("{}: lag cycles: {} exceeds or reached max introduced lag cycles. data count : {}. proceeding for ingestion.", self.name, max_lag_cycles, data.len());
                                 break 'inner;
                             } else {
                                 trace!("{}: introducing lag", self.name);
-                                introduce_lag(self.introduced_lag_in_millies).await;
-                                trace!("{}: introduced lag successfull", self.name);
+                                tokio::select! {
+                                    _ = introduce_lag(self.clock(), self.introduced_lag_in_millies) => trace!("{}: introduced lag successfull", self.name),
+                                    _ = self.flush_requested.notified() => {
+                                        data.append(&mut self.take_queued(rx));
+                                        remove_duplicates(&mut data);
+                                        flushing = true;
+                                        break 'inner;
+                                    },
+                                    _ = self.cancellation_token.cancelled() => {
+                                        info!("{}: cancellation received during lag cycles. data count : {}. proceeding for ingestion.", self.name, data.len());
+                                        cancelled = true;
+                                        break 'inner;
+                                    },
+                                }
                             }
                         },
                     }
                 };
 
+                quota_reached = self.take_quota(&mut data);
+
                 trace!("{}: splitting vectors for batch ingestion", self.name);
-                let vec_data = split_vec(data);
+                let vec_data = self.split_by_variant(data);
                 trace!("{}: splitting vectors complete. batch count: {}", self.name, vec_data.len());
 
                 trace!("{}: data ingestion starting for batches", self.name);
-                self.push_to_handle(&mut senders, vec_data, &mut tx_count).await;
+                self.push_to_handle(&mut senders, vec_data, &mut tx_count).await.map_err(|error| run_guard.fail(error))?;
                 trace!("{}: data pushed for ingestion", self.name);
             }
 
             self.rebalance_senders(&mut senders, &mut tx_count);
+
+            if flushing {
+                self.place_flush_barriers();
+            }
+
+            if quota_reached {
+                info!("{}: max total rows {} reached, main channel receiver stopping", self.name, self.total_rows());
+                break;
+            }
+
+            if cancelled {
+                info!("{}: buffered data pushed for ingestion, main channel receiver stopping", self.name);
+                break;
+            }
+        }
+
+        info!("{}: main channel receiver stopped, data ingestors are draining", self.name);
+        run_guard.finished = true;
+        self.set_state(StreamState::Draining);
+        let connections = senders.values().map(Vec::len).sum::<usize>();
+        if cancelled {
+            self.join_senders(senders).await;
+            self.set_state(StreamState::Stopped);
+            if let Some(completed) = completed {
+                let _ = completed.send(self.shutdown_report(connections, true));
+            }
+        } else {
+            let self_clone = self.to_owned();
+            self.spawn(async move {
+                self_clone.join_senders(senders).await;
+                self_clone.set_state(StreamState::Stopped);
+                if let Some(completed) = completed {
+                    let _ = completed.send(self_clone.shutdown_report(connections, true));
+                }
+            });
         }
+
+        let shutdown_report = self.shutdown_report(connections, cancelled);
+        info!("{}: upsert quick stream stopped, {:?}", self.name, shutdown_report);
+        Ok(shutdown_report)
     }
 
-    async fn get_db_client(&self) -> Client {
-        trace!("{}: creating database client", self.name);
-        let config = self.db_config.to_owned();
+    /**
+     Changes a parameter of the stream while it runs. Shared between all clones of the stream.
+     * Validated right away, but applied by the main channel receiver before it processes the next payload, never in the middle of one
+     */
+    pub fn tune(&self, tuning: Tuning) -> Result<(), QuickStreamError> {
+        match tuning {
+            Tuning::MaxConnectionCount(max_con_count) => {
+                let minimum = self.tiers().iter().map(|(_, init_limit)| init_limit).sum::<usize>();
+                if max_con_count < minimum {
+                    return Err(QuickStreamError::InvalidTuning(format!("max connection count {} is below the {} initial senders of the tiers", max_con_count, minimum)))
+                }
+            },
+            Tuning::ConnectionCreationThreshold(connection_creation_threshold) => {
+                if !(0f64..=100f64).contains(&connection_creation_threshold) {
+                    return Err(QuickStreamError::InvalidTuning(format!("connection creation threshold {} is not a percentage", connection_creation_threshold)))
+                }
+            },
+        }
 
-        match &self.tls {
-            Some(tls) => {
-                trace!("{}: tls is enabled", self.name);
-                trace!("{}: creating tls connector", self.name);
-                let connector = TlsConnector::builder()
-                    .add_root_certificate(tls.clone())
-                    .build()
-                    .unwrap();
+        info!("{}: {:?} will be applied with the next payload", self.name, tuning);
+        self.pending_tunings.lock().unwrap().push(tuning);
+        Ok(())
+    }
 
-                let tls = MakeTlsConnector::new(connector);
+    fn apply_tunings(&self) {
+        for tuning in self.pending_tunings.lock().unwrap().drain(..) {
+            info!("{}: applying {:?}", self.name, tuning);
+            match tuning {
+                Tuning::MaxConnectionCount(max_con_count) => self.max_con_count.store(max_con_count, Ordering::Relaxed),
+                Tuning::ConnectionCreationThreshold(connection_creation_threshold) => self.connection_creation_threshold.store(connection_creation_threshold.to_bits(), Ordering::Relaxed),
+            }
+        }
+    }
 
-                trace!("{}: creating tls connector success", self.name);
+    pub fn max_con_count(&self) -> usize {
+        self.max_con_count.load(Ordering::Relaxed)
+    }
 
-                trace!("{}: establishing database connection with tls", self.name);
-                let (client, connection) = match config
-                    .connect(tls)
-                    .await {
-                    Ok(cnc) => cnc,
-                    Err(error) => panic!("error occured during database client establishment with tls, error : {}", error)
-                };
-                trace!("{}: establishing database connection with tls success", self.name);
-        
-                trace!("{}: creating thread to hold the database connection with tls", self.name);
-                tokio::spawn(async move {
-                    if let Err(error) = connection.await {
-                        eprintln!("connection failed with error : {}", error)
-                    }
-                });
-        
-                trace!("{}: creating database client with tls success, returning client", self.name);
-                client                
-            },
-            None => {
-                trace!("{}: tls is dissabled", self.name);
+    pub fn connection_creation_threshold(&self) -> f64 {
+        f64::from_bits(self.connection_creation_threshold.load(Ordering::Relaxed))
+    }
 
-                trace!("{}: establishing database connection", self.name);
-                let (client, connection) = match config
-                    .connect(NoTls)
-                    .await {
-                    Ok(cnc) => cnc,
-                    Err(error) => panic!("error occured during database client establishment, error : {}", error)
-                };
-                trace!("{}: establishing database connection success", self.name);
-        
-                trace!("{}: creating thread to hold the database connection", self.name);
-                tokio::spawn(async move {
-                    if let Err(error) = connection.await {
-                        eprintln!("connection failed with error : {}", error)
-                    }
-                });
-                trace!("{}: creating thread to hold the database connection success", self.name);
-        
-                trace!("{}: creating database client success, returning client", self.name);
-                client
+    /**
+     Where the stream is in its lifecycle, for health checks of the application. Shared between all clones of the stream.
+     * `Draining` lasts until every data ingestor stopped, also when `run` returned right after the main channel closed
+     */
+    pub fn state(&self) -> StreamState {
+        StreamState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: StreamState) {
+        trace!("{}: stream state {:?}", self.name, state);
+        self.state.store(state.as_u8(), Ordering::Release);
+    }
+
+    fn shutdown_report(&self, connections: usize, drained: bool) -> ShutdownReport {
+        ShutdownReport {
+            rows_accepted: self.total_rows(),
+            rows_written: self.written_rows(),
+            connections,
+            drained,
+        }
+    }
+
+    /// Fails when rows are forwarded with `QuickStreamBuilder::returning` but the records do not implement `Upsert::upsert_returning`
+    fn verify_returning<T>(&self) -> Result<(), QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        match self.returning.is_some() && !T::RETURNING {
+            true => {
+                error!("{}: returning is set, but Upsert::upsert_returning is not implemented", self.name);
+                Err(QuickStreamError::ReturningUnsupported)
             },
+            false => Ok(()),
         }
     }
 
-    async fn process_n<T>(&self, query: String, mut rx: Receiver<Vec<T>>, thread_id: i64, n: usize) -> Result<(), Error>  where T: Upsert<T> + Clone + Send + 'static {
-        info!("{}:{}:{}: starting data ingestor", self.name, n, thread_id);
+    /// Rows of the batches written successfully, across all connections. Shared between all clones of the stream.
+    pub fn written_rows(&self) -> u64 {
+        self.written_rows.load(Ordering::Relaxed)
+    }
+
+    /// Writes every payload on a single connection in the order received, see `QuickStreamBuilder::strict_fifo`
+    async fn run_fifo<T>(&self, rx: &mut Receiver<Vec<T>>) -> Result<ShutdownReport, QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        info!("{}: strict fifo, creating database client", self.name);
+        let client = self.get_db_client().await?;
+        let mut statements = HashMap::new();
+        for (n, _) in self.tiers() {
+            let statement = self.prepare(&client, &self.queries.get(&n)?, 0, n).await?;
+            statements.insert(n, statement);
+        }
+        info!("{}: strict fifo, statements of {} tiers prepared", self.name, statements.len());
+
+        loop {
+            let (mut data, flushing) = tokio::select! {
+                data = rx.recv() => match data {
+                    Some(data) => (data, false),
+                    None => break,
+                },
+                _ = self.flush_requested.notified() => (self.take_queued(rx), true),
+                _ = self.cancellation_token.cancelled() => {
+                    info!("{}: cancellation received, main channel receiver stopping", self.name);
+                    break
+                },
+            };
+
+            remove_duplicates_keep_order(&mut data);
+            let quota_reached = self.take_quota(&mut data);
+
+            #[cfg(feature = "tracing")]
+            let payload_span = tracing::info_span!("quick_stream.payload", stream = %self.name, records = data.len());
+            for batch in self.split(data) {
+                let n = batch.len();
+                let pushed_batch = self.next_batch(n, #[cfg(feature = "tracing")] &payload_span);
+                if self.dry_run {
+                    info!("{}: dry run, skipping upsert of {} records. pkeys: {:?}", self.name, n, batch.iter().map(|f| f.pkey()).collect::<Vec<i64>>());
+                    self.settle_batch(pushed_batch, true);
+                    continue;
+                }
+
+                trace!("{}: strict fifo, writing batch of {} records", self.name, n);
+                let started = self.clock().now();
+                let Some(statement) = statements.get(&n) else {
+                    error!("{}: strict fifo, no statement of tier {}, check quick_stream::split_vec<T>(data: Vec<T>) function", self.name, n);
+                    self.settle_batch(pushed_batch, false);
+                    return Err(QuickStreamError::MissingTier { tier: n })
+                };
+                #[cfg(feature = "tracing")]
+                let upsert_span = tracing::info_span!(parent: &pushed_batch.span, "quick_stream.upsert", stream = %self.name, tier = n, sender = 0, batches = 1);
+                let result = T::upsert(&client, batch, statement, 0).await;
+                #[cfg(feature = "tracing")]
+                drop(upsert_span);
+                if let Err(error) = result {
+                    self.settle_batch(pushed_batch, false);
+                    return Err(self.upsert_error(error, n, 0))
+                }
+                self.written_rows.fetch_add(n as u64, Ordering::Relaxed);
+                self.settle_batch(pushed_batch, true);
+                self.observe_batch_duration(n, 0, n, self.clock().now().saturating_duration_since(started));
+            }
+
+            if flushing {
+                self.place_flush_barriers();
+            }
+
+            if quota_reached {
+                info!("{}: max total rows {} reached, main channel receiver stopping", self.name, self.total_rows());
+                break;
+            }
+        }
+
+        info!("{}: strict fifo, main channel receiver stopped", self.name);
+        // every batch is written before the next payload is received
+        Ok(self.shutdown_report(1, true))
+    }
+
+    /// Closes every sender and waits until its data ingestor wrote what is left in its channel
+    async fn join_senders<T>(&self, senders: HashMap<usize, Vec<UpsertData<T>>>) where T: Upsert<T> + Clone + Send + 'static {
+        // dropping the senders first lets the data ingestors drain in parallel
+        let join_handlers = senders.into_values().flatten().map(|sender| (sender.type_, sender.id, sender.join_handler)).collect::<Vec<_>>();
+        info!("{}: waiting for {} data ingestors to drain", self.name, join_handlers.len());
+        for (type_, id, join_handler) in join_handlers {
+            if let Err(error) = join_handler.await {
+                error!("{}: data ingestor {}:{} failed while draining : {}", self.name, type_, id, error);
+            }
+        }
+        info!("{}: all data ingestors drained", self.name);
+    }
+
+    /**
+     Runs the stream like `run`, restarting it after the backoff of the restart policy whenever it fails, e.g. on a database outage
+     while starting or when every data ingestor of a tier stopped. Fails with the error of the last run once the restarts are used up.
+     * Every restart reuses the configuration and the main channel, payloads pushed while the stream restarts wait in the main channel.
+       The channels of the data ingestors are created anew, batches queued to them when the stream failed are not written
+     * Errors which persist across restarts, e.g. a query which fails to prepare, fail the same way on every restart
+     * Not restarted after cancellation, nor when `run` panics
+     */
+    pub async fn run_supervised<T>(&self, rx: Receiver<Vec<T>>, restart_policy: RestartPolicy) -> Result<ShutdownReport, QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        let mut rx = rx;
+        let mut restarts = 0;
+        loop {
+            let error = match self.run_with_completion(&mut rx, None).await {
+                Ok(shutdown_report) => return Ok(shutdown_report),
+                Err(error) => error,
+            };
+
+            if restarts >= restart_policy.max_restarts || self.cancellation_token.is_cancelled() {
+                error!("{}: stream failed, not restarting after {} restarts, error : {}", self.name, restarts, error);
+                return Err(error)
+            }
+
+            let backoff = restart_policy.backoff(restarts);
+            restarts += 1;
+            error!("{}: stream failed, restarting in {:?} ({}/{}), error : {}", self.name, backoff, restarts, restart_policy.max_restarts, error);
+            tokio::select! {
+                _ = self.clock().sleep(backoff) => info!("{}: restarting stream", self.name),
+                _ = self.cancellation_token.cancelled() => {
+                    info!("{}: cancellation received while waiting to restart the stream", self.name);
+                    return Err(error)
+                },
+            }
+        }
+    }
+
+    /**
+     Runs the stream fed by several producer channels instead of one.
+     * Payloads are ingested in the order they arrive. The order of a single producer is kept, but producers are interleaved on a best effort basis.
+     * The stream stops when all producer channels are closed.
+     */
+    pub async fn run_many<T>(&self, receivers: Vec<Receiver<Vec<T>>>) -> Result<ShutdownReport, QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        info!("{}: merging {} producer channels", self.name, receivers.len());
+        let rx = self.fan_in(receivers);
+        self.run(rx).await
+    }
+
+    /// Forwards every payload of the given receivers into a single channel with the configured buffer size. Payloads are interleaved
+    /// in the order they arrive, which keeps the order of a single producer but not across producers.
+    /// The returned receiver closes once all of the given receivers are closed.
+    fn fan_in<T>(&self, receivers: Vec<Receiver<Vec<T>>>) -> Receiver<Vec<T>> where T: Send + 'static {
+        let (tx, rx) = mpsc::channel::<Vec<T>>(self.buffer_size);
+
+        for (producer, mut receiver) in receivers.into_iter().enumerate() {
+            let tx = tx.clone();
+            let name = self.name.to_owned();
+            self.spawn(async move {
+                while let Some(data) = receiver.recv().await {
+                    if tx.send(data).await.is_err() {
+                        break;
+                    }
+                }
+                trace!("{}: producer channel {} closed", name, producer);
+            });
+        }
+
+        rx
+    }
+
+    /**
+     Creates the main channel with the configured buffer size and spawns `run` with its receiver.
+     * Returns the sender to push payloads into and a handle to wait for the stream. Dropping every clone of the sender stops the stream.
+     * Producers which must never wait for the stream can push with `Sender::try_send`, which fails right away with the payload when the main channel is full
+     */
+    pub fn start<T>(&self) -> (Sender<Vec<T>>, StreamHandle) where T: Upsert<T> + Clone + Send + 'static {
+        let (tx, rx) = mpsc::channel::<Vec<T>>(self.buffer_size);
+        let (completed_tx, completed) = oneshot::channel();
+        let self_clone = self.to_owned();
+        let join_handle = self.spawn(async move {
+            let mut rx = rx;
+            self_clone.run_with_completion(&mut rx, Some(completed_tx)).await
+        });
+
+        (tx, StreamHandle { name: self.name.to_owned(), join_handle, completed })
+    }
+
+    async fn get_db_client(&self) -> Result<Client, QuickStreamError> {
+        let (client, _closed) = self.connect().await?;
+        Ok(client)
+    }
+
+    /// Creates a database client along with a receiver which completes as soon as the task holding its connection stops
+    async fn connect(&self) -> Result<(Client, oneshot::Receiver<()>), QuickStreamError> {
+        trace!("{}: creating database client", self.name);
+        let config = self.db_config.to_owned();
+        let (closed_tx, closed) = oneshot::channel();
+
+        let tls = self.tls.read().unwrap().clone();
+        let client = match tls {
+            Some(tls) => {
+                trace!("{}: tls is enabled", self.name);
+                trace!("{}: creating tls connector", self.name);
+                let connector = match TlsConnector::builder()
+                    .add_root_certificate(tls)
+                    .build() {
+                    Ok(connector) => connector,
+                    Err(error) => {
+                        error!("{}: error occured while creating tls connector, error : {}", self.name, error);
+                        return Err(QuickStreamError::Tls(error))
+                    }
+                };
+
+                let tls = MakeTlsConnector::new(connector);
+
+                trace!("{}: creating tls connector success", self.name);
+
+                trace!("{}: establishing database connection with tls", self.name);
+                let (client, connection) = match self.with_connect_timeout(config
+                    .connect(tls))
+                    .await {
+                    Ok(cnc) => cnc,
+                    Err(error) => {
+                        error!("{}: error occured during database client establishment with tls, error : {}", self.name, error);
+                        return Err(error)
+                    }
+                };
+                trace!("{}: establishing database connection with tls success", self.name);
+        
+                trace!("{}: creating thread to hold the database connection with tls", self.name);
+                let name = self.name.to_owned();
+                self.spawn(async move {
+                    if let Err(error) = connection.await {
+                        error!("{}: database connection with tls failed with error : {}", name, error)
+                    }
+                    let _ = closed_tx.send(());
+                });
+        
+                trace!("{}: creating database client with tls success, returning client", self.name);
+                client                
+            },
+            None => {
+                trace!("{}: tls is dissabled", self.name);
+
+                trace!("{}: establishing database connection", self.name);
+                let (client, connection) = match self.with_connect_timeout(config
+                    .connect(NoTls))
+                    .await {
+                    Ok(cnc) => cnc,
+                    Err(error) => {
+                        error!("{}: error occured during database client establishment, error : {}", self.name, error);
+                        return Err(error)
+                    }
+                };
+                trace!("{}: establishing database connection success", self.name);
+        
+                trace!("{}: creating thread to hold the database connection", self.name);
+                let name = self.name.to_owned();
+                self.spawn(async move {
+                    if let Err(error) = connection.await {
+                        error!("{}: database connection failed with error : {}", name, error)
+                    }
+                    let _ = closed_tx.send(());
+                });
+                trace!("{}: creating thread to hold the database connection success", self.name);
+        
+                trace!("{}: creating database client success, returning client", self.name);
+                client
+            },
+        };
+
+        if let Some(session_variable) = &self.session_variable {
+            trace!("{}: setting session variable {}", self.name, session_variable);
+            if let Err(error) = client.execute("SELECT set_config($1, $2, false)", &[session_variable, &self.name]).await {
+                error!("{}: error occured while setting session variable {}, error : {}", self.name, session_variable, error);
+                return Err(QuickStreamError::Database(error))
+            }
+        }
+
+        Ok((client, closed))
+    }
+
+    /**
+     Replaces the root certificate used for new database connections, e.g. after the certificate authority rotated.
+     Established connections keep the certificate they were created with. Shared between all clones of the stream.
+     * Enables tls for new connections if the stream was built without a certificate
+     */
+    pub fn update_tls(&self, tls: Certificate) {
+        info!("{}: updating tls root certificate for new connections", self.name);
+        *self.tls.write().unwrap() = Some(tls);
+    }
+
+    /// Spawns on the runtime given to the builder, or on the current runtime
+    fn spawn<F>(&self, future: F) -> JoinHandle<F::Output> where F: Future + Send + 'static, F::Output: Send + 'static {
+        match &self.runtime_handle {
+            Some(runtime_handle) => runtime_handle.spawn(future),
+            None => tokio::spawn(future),
+        }
+    }
+
+    async fn with_connect_timeout<F, C>(&self, connect: F) -> Result<C, QuickStreamError> where F: Future<Output = Result<C, Error>> {
+        match self.connect_timeout {
+            Some(connect_timeout) => tokio::select! {
+                output = connect => Ok(output?),
+                _ = self.clock().sleep(connect_timeout) => {
+                    error!("{}: database connection could not be established within {:?}", self.name, connect_timeout);
+                    Err(QuickStreamError::ConnectTimeout(connect_timeout))
+                },
+            },
+            None => Ok(connect.await?),
+        }
+    }
+
+    async fn prepare_ingestor(&self, thread_id: i64, n: usize) -> Result<PreparedIngestor, QuickStreamError> {
+        let query = self.queries.get(&self.statement_tier(n))?;
+        info!("{}:{}:{}: creating database client", self.name, n, thread_id);
+        let (client, closed) = self.connect().await?;
+        let connected_at = self.clock().now();
+        info!("{}:{}:{}: creating database client success", self.name, n, thread_id);
+
+        info!("{}:{}:{}: preparing query and creating statement", self.name, n, thread_id);
+        // tokio_postgres names prepared statements itself (s0, s1, ...) and has no api to name them, so they can not be
+        // prefixed with the stream name. Correlate them in pg_prepared_statements through the session variable instead.
+        let statement = self.prepare(&client, &query, thread_id, n).await?;
+        info!("{}:{}:{}: query prepared and created statement successfully", self.name, n, thread_id);
+
+        let do_nothing_statement = match &self.do_nothing_queries {
+            Some(do_nothing_queries) => {
+                info!("{}:{}:{}: preparing do nothing query and creating statement", self.name, n, thread_id);
+                let do_nothing_statement = self.prepare(&client, &do_nothing_queries.get(&self.statement_tier(n))?, thread_id, n).await?;
+                info!("{}:{}:{}: do nothing query prepared and created statement successfully", self.name, n, thread_id);
+                Some(do_nothing_statement)
+            },
+            None => None,
+        };
+
+        let dual_write_statement = match &self.dual_write_queries {
+            Some(dual_write_queries) => {
+                info!("{}:{}:{}: preparing dual write query and creating statement", self.name, n, thread_id);
+                let dual_write_statement = self.prepare(&client, &dual_write_queries.get(&self.statement_tier(n))?, thread_id, n).await?;
+                info!("{}:{}:{}: dual write query prepared and created statement successfully", self.name, n, thread_id);
+                Some(dual_write_statement)
+            },
+            None => None,
+        };
+
+        let mut variant_statements = HashMap::new();
+        for (variant, variant_queries) in &self.variant_queries {
+            info!("{}:{}:{}: preparing query of statement variant {} and creating statement", self.name, n, thread_id, variant);
+            let variant_statement = self.prepare(&client, &variant_queries.get(&self.statement_tier(n))?, thread_id, n).await?;
+            variant_statements.insert(variant.to_owned(), variant_statement);
+        }
+
+        Ok(PreparedIngestor { query, client, statement, do_nothing_statement, dual_write_statement, variant_statements, closed, connected_at })
+    }
+
+    /// Tier whose queries the data ingestors of tier `n` prepare, which is tier 1 for every tier when executing single rows
+    fn statement_tier(&self, n: usize) -> usize {
+        match self.execution_mode {
+            ExecutionMode::MultiRow => n,
+            ExecutionMode::SingleRowPipelined => 1,
+        }
+    }
+
+    /// Prepares a query of the tier, failing with the query so it is clear which one of the tiers is wrong
+    async fn prepare(&self, client: &Client, query: &str, thread_id: i64, n: usize) -> Result<Statement, QuickStreamError> {
+        client.prepare(query).await.map_err(|error| {
+            error!("{}:{}:{}: failed to prepare query : {}, error : {}", self.name, n, thread_id, query, error);
+            QuickStreamError::Prepare { tier: n, query: query.to_owned(), source: error }
+        })
+    }
+
+    /// Switches the queries executed by every data ingestor, starting with their next batch. Shared between all clones of the stream.
+    /// Panics when switching to `ConflictMode::DoNothing` without `do_nothing_queries`.
+    pub fn set_conflict_mode(&self, conflict_mode: ConflictMode) {
+        if conflict_mode == ConflictMode::DoNothing {
+            self.do_nothing_queries.as_ref().expect("do_nothing_queries is None");
+        }
+        info!("{}: switching conflict mode to {:?}", self.name, conflict_mode);
+        self.conflict_mode.store(conflict_mode.into(), Ordering::Release);
+    }
+
+    pub fn conflict_mode(&self) -> ConflictMode {
+        match self.conflict_mode.load(Ordering::Acquire) {
+            true => ConflictMode::DoNothing,
+            false => ConflictMode::DoUpdate,
+        }
+    }
+
+    async fn process_n<T>(&self, mut rx: Receiver<Vec<T>>, thread_id: i64, n: usize, in_flight: Arc<InFlight>, ready: Option<oneshot::Sender<Result<(), QuickStreamError>>>) -> Result<(), QuickStreamError>  where T: Upsert<T> + Clone + Send + 'static {
+        let result = self.ingest_n(&mut rx, thread_id, n, in_flight.clone(), ready).await;
+        if let Err(error) = &result {
+            if error.is_too_many_connections() {
+                self.pause_scale_up(thread_id, n);
+            }
+            // keeps the error sent through the readiness barrier, which is more telling than the one returned
+            in_flight.last_error.lock().unwrap().get_or_insert_with(|| error.to_string());
+            self.discard_queued(&mut rx, &in_flight, n, thread_id);
+            // the batch which failed and the discarded batches
+            self.fail_batches(&in_flight);
+        }
+        result
+    }
+
+    /// Drops the batches left in the channel of a data ingestor which stopped, releasing them from the records in flight
+    fn discard_queued<T>(&self, rx: &mut Receiver<Vec<T>>, in_flight: &InFlight, n: usize, thread_id: i64) {
+        // closing first makes every later send fail back to the processor, so no batch is missed
+        rx.close();
+        let mut discarded = 0;
+        while let Ok(data) = rx.try_recv() {
+            discarded += data.len();
+            self.release_in_flight(in_flight, data.len());
+        }
+        if discarded > 0 {
+            warn!("{}:{}:{}: data ingestor stopped, discarding {} queued records", self.name, n, thread_id, discarded);
+        }
+    }
+
+    async fn ingest_n<T>(&self, rx: &mut Receiver<Vec<T>>, thread_id: i64, n: usize, in_flight: Arc<InFlight>, ready: Option<oneshot::Sender<Result<(), QuickStreamError>>>) -> Result<(), QuickStreamError>  where T: Upsert<T> + Clone + Send + 'static {
+        info!("{}:{}:{}: starting data ingestor", self.name, n, thread_id);
+
+        let prepared = self.prepare_ingestor(thread_id, n).await;
+        let mut prepared = match ready {
+            Some(ready) => match prepared {
+                Ok(prepared) => {
+                    let _ = ready.send(Ok(()));
+                    prepared
+                },
+                Err(error) => {
+                    if error.is_too_many_connections() {
+                        self.pause_scale_up(thread_id, n);
+                    }
+                    // the error is returned from run through the readiness barrier, the data ingestor still stops with it
+                    *in_flight.last_error.lock().unwrap() = Some(error.to_string());
+                    let _ = ready.send(Err(error));
+                    return Err(QuickStreamError::IngestorStopped { tier: n, id: thread_id })
+                },
+            },
+            None => prepared?,
+        };
+
+        info!("{}:{}:{}: data ingestor channel receiver starting", self.name, n, thread_id);
+        loop {
+            // the cancellation token is deliberately not part of this select, nor raced against the upsert below.
+            // a data ingestor only stops once its sender is dropped and its channel is drained, so an upsert which
+            // started is always awaited to its result and shutdown never leaves a batch of unknown outcome
+            let mut data = tokio::select! {
+                data = rx.recv() => match data {
+                    Some(data) => data,
+                    None => break,
+                },
+                _ = &mut prepared.closed => {
+                    // reconnect as soon as the connection is lost, rather than with the next batch
+                    warn!("{}:{}:{}: database connection closed, reconnecting", self.name, n, thread_id);
+                    prepared = self.prepare_ingestor(thread_id, n).await?;
+                    continue;
+                }
+            };
+
+            if data.is_empty() {
+                trace!("{}:{}:{}: empty batch received, skipping", self.name, n, thread_id);
+                continue;
+            }
+
+            trace!("{}:{}:{}: data received pushing for ingestion. pkeys: {:?}", self.name, n, thread_id, data.iter().map(|f| f.pkey()).collect::<Vec<i64>>());
+            let batch_size = data.len();
+            if self.dry_run {
+                info!("{}:{}:{}: dry run, skipping upsert of {} records. query: {}. pkeys: {:?}", self.name, n, thread_id, data.len(), prepared.query, data.iter().map(|f| f.pkey()).collect::<Vec<i64>>());
+                self.release_in_flight(&in_flight, batch_size);
+                self.confirm_next_batch(&in_flight);
+                continue;
+            }
+
+            // the task holding the connection stopped, reconnect instead of failing the batch
+            if prepared.client.is_closed() {
+                warn!("{}:{}:{}: database connection is closed, reconnecting", self.name, n, thread_id);
+                match self.prepare_ingestor(thread_id, n).await {
+                    Ok(reconnected) => prepared = reconnected,
+                    Err(error) => {
+                        self.release_in_flight(&in_flight, batch_size);
+                        return Err(error)
+                    },
+                }
+            } else if self.connection_expired(prepared.connected_at) {
+                info!("{}:{}:{}: database connection reached its max lifetime, reconnecting", self.name, n, thread_id);
+                match self.prepare_ingestor(thread_id, n).await {
+                    Ok(reconnected) => prepared = reconnected,
+                    // the current connection still works, so the batch is written with it
+                    Err(error) => warn!("{}:{}:{}: failed to replace the database connection which reached its max lifetime, keeping it, error : {}", self.name, n, thread_id, error),
+                }
+            }
+
+            if let Some(commit_batch_size) = self.commit_batch_size.filter(|commit_batch_size| *commit_batch_size > 1) {
+                let mut more_batches = vec![];
+                while more_batches.len() + 1 < commit_batch_size {
+                    match rx.try_recv() {
+                        Ok(more) if more.is_empty() => continue,
+                        Ok(more) => more_batches.push(more),
+                        Err(_) => break,
+                    }
+                }
+
+                if !more_batches.is_empty() {
+                    let group = std::iter::once(data).chain(more_batches).collect::<Vec<Vec<T>>>();
+                    self.write_commit_group(&prepared, group, n, thread_id, &in_flight).await?;
+                    continue;
+                }
+            }
+
+            let max_modified_date = match self.watermark_query {
+                Some(_) => data.iter().map(|record| record.modified_date()).max(),
+                None => None,
+            };
+            let started = self.clock().now();
+            #[cfg(feature = "tracing")]
+            let upsert_span = self.upsert_span(&in_flight, n, thread_id, 1);
+            let mut read_only_retries = 0;
+            let result = loop {
+                let statement = match self.batch_statement(&prepared, &data) {
+                    Ok(statement) => statement,
+                    Err(error) => {
+                        self.release_in_flight(&in_flight, batch_size);
+                        return Err(error)
+                    },
+                };
+                // upsert takes the batch, so it is only copied while it can still be retried
+                let retry = match self.read_only_reconnect_delay {
+                    Some(_) if read_only_retries < self.read_only_max_retries && !self.cancellation_token.is_cancelled() => Some(data.clone()),
+                    _ => None,
+                };
+                let result = match &prepared.dual_write_statement {
+                    Some(dual_write_statement) => self.upsert_dual_write(&prepared.client, data, statement, dual_write_statement, thread_id).await,
+                    None => self.execute_batch(&prepared.client, data, statement, thread_id).await,
+                };
+
+                match (result, retry, self.read_only_reconnect_delay) {
+                    (Err(error), Some(retry), Some(read_only_reconnect_delay)) if error.code() == Some(&SqlState::READ_ONLY_SQL_TRANSACTION) => {
+                        // the server was demoted, e.g. by a failover, only a new connection can reach the new primary
+                        read_only_retries += 1;
+                        warn!("{}:{}:{}: database is read only, reconnecting in {:?} to retry the batch ({}/{}), error : {}", self.name, n, thread_id, read_only_reconnect_delay, read_only_retries, self.read_only_max_retries, error);
+                        tokio::select! {
+                            _ = self.clock().sleep(read_only_reconnect_delay) => {},
+                            _ = self.cancellation_token.cancelled() => {
+                                warn!("{}:{}:{}: cancellation received while waiting to retry the batch on a read only database, failing it", self.name, n, thread_id);
+                                break Err(error)
+                            },
+                        }
+                        match self.prepare_ingestor(thread_id, n).await {
+                            Ok(reconnected) => prepared = reconnected,
+                            Err(error) => {
+                                self.release_in_flight(&in_flight, batch_size);
+                                return Err(error)
+                            },
+                        }
+                        data = retry;
+                    },
+                    (Err(error), None, Some(_)) if error.code() == Some(&SqlState::READ_ONLY_SQL_TRANSACTION) => {
+                        match read_only_retries < self.read_only_max_retries {
+                            // no retry was kept, as cancellation was received before the write
+                            true => warn!("{}:{}:{}: database is read only and cancellation was received, failing the batch after {} retries, error : {}", self.name, n, thread_id, read_only_retries, error),
+                            false => error!("{}:{}:{}: database is still read only after {} retries, failing the batch, error : {}", self.name, n, thread_id, read_only_retries, error),
+                        }
+                        break Err(error)
+                    },
+                    (result, _, _) => break result,
+                }
+            };
+            #[cfg(feature = "tracing")]
+            drop(upsert_span);
+            self.observe_watermark(max_modified_date, result.is_ok());
+            self.release_in_flight(&in_flight, batch_size);
+            let (_, returned) = result.map_err(|error| self.upsert_error(error, n, thread_id))?;
+            self.written_rows.fetch_add(batch_size as u64, Ordering::Relaxed);
+            self.forward_returned(returned, n, thread_id).await;
+            in_flight.progress(self.clock().now());
+            self.confirm_next_batch(&in_flight);
+            self.observe_batch_duration(n, thread_id, batch_size, self.clock().now().saturating_duration_since(started));
+        }
+
+        info!("{}:{}:{} shutting down data ingestor", self.name, n, thread_id);
+        Ok(())
+    }
+
+    /// Whether a connection created at `connected_at` outlived `max_connection_lifetime`
+    fn connection_expired(&self, connected_at: Instant) -> bool {
+        self.max_connection_lifetime.is_some_and(|max_connection_lifetime| self.clock().now().saturating_duration_since(connected_at) >= max_connection_lifetime)
+    }
+
+    /// Statement writing the batch, by its statement variant and the conflict mode. Batches hold a single variant, see `split_by_variant`
+    fn batch_statement<'a, T>(&self, prepared: &'a PreparedIngestor, data: &[T]) -> Result<&'a Statement, QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        match (data.first().and_then(|record| record.statement_variant()), self.conflict_mode(), &prepared.do_nothing_statement) {
+            // the variant comes from the records, so it can not be checked while building the stream
+            (Some(variant), _, _) => prepared.variant_statements.get(variant).ok_or_else(|| QuickStreamError::MissingVariant { variant: variant.to_owned() }),
+            (None, ConflictMode::DoNothing, Some(do_nothing_statement)) => Ok(do_nothing_statement),
+            _ => Ok(&prepared.statement),
+        }
+    }
+
+    /// Writes several batches in one transaction, see `QuickStreamBuilder::commit_batch_size`. Accounts for every batch once the group is committed.
+    async fn write_commit_group<T>(&self, prepared: &PreparedIngestor, group: Vec<Vec<T>>, n: usize, thread_id: i64, in_flight: &InFlight) -> Result<(), QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        let batch_sizes = group.iter().map(Vec::len).collect::<Vec<usize>>();
+        let rows = batch_sizes.iter().sum::<usize>();
+        trace!("{}:{}:{}: writing {} batches of {} records in one transaction", self.name, n, thread_id, group.len(), rows);
+        let max_modified_date = match self.watermark_query {
+            Some(_) => group.iter().flatten().map(|record| record.modified_date()).max(),
+            None => None,
+        };
+        let statements = match group.iter().map(|data| self.batch_statement(prepared, data)).collect::<Result<Vec<&Statement>, QuickStreamError>>() {
+            Ok(statements) => statements,
+            Err(error) => {
+                self.release_in_flight(in_flight, rows);
+                return Err(error)
+            },
+        };
+        let started = self.clock().now();
+        #[cfg(feature = "tracing")]
+        let upsert_span = self.upsert_span(in_flight, n, thread_id, batch_sizes.len());
+        let result = self.execute_commit_group(prepared, group, statements, thread_id).await;
+        #[cfg(feature = "tracing")]
+        drop(upsert_span);
+        self.observe_watermark(max_modified_date, result.is_ok());
+        self.release_in_flight(in_flight, rows);
+        let returned = result.map_err(|error| self.upsert_error(error, n, thread_id))?;
+        self.written_rows.fetch_add(rows as u64, Ordering::Relaxed);
+        for returned in returned {
+            self.forward_returned(returned, n, thread_id).await;
+        }
+        in_flight.progress(self.clock().now());
+        for _ in batch_sizes {
+            self.confirm_next_batch(in_flight);
+        }
+        self.observe_batch_duration(n, thread_id, rows, self.clock().now().saturating_duration_since(started));
+        Ok(())
+    }
+
+    /// Executes every batch of the group in one transaction, rolling all of them back when one fails. Returns the returned rows of every batch.
+    async fn execute_commit_group<T>(&self, prepared: &PreparedIngestor, group: Vec<Vec<T>>, statements: Vec<&Statement>, thread_id: i64) -> Result<Vec<Vec<Row>>, Error> where T: Upsert<T> + Clone + Send + 'static {
+        prepared.client.batch_execute("BEGIN").await?;
+        let mut returned = vec![];
+        let mut result = Ok(());
+        for (data, statement) in group.into_iter().zip(statements) {
+            // dual writes share the transaction of the group instead of opening one of their own
+            let dual_write = prepared.dual_write_statement.as_ref().map(|dual_write_statement| (dual_write_statement, data.clone()));
+            match self.execute_batch(&prepared.client, data, statement, thread_id).await {
+                Ok((_, rows)) => returned.push(rows),
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                },
+            }
+            if let Some((dual_write_statement, data)) = dual_write {
+                if let Err(error) = self.execute_batch(&prepared.client, data, dual_write_statement, thread_id).await {
+                    result = Err(error);
+                    break;
+                }
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                prepared.client.batch_execute("COMMIT").await?;
+                Ok(returned)
+            },
+            Err(error) => {
+                if let Err(rollback_error) = prepared.client.batch_execute("ROLLBACK").await {
+                    warn!("{}:{}: failed to roll back commit group : {}", self.name, thread_id, rollback_error);
+                }
+                Err(error)
+            },
+        }
+    }
+
+    /// Executes the batch, returning the affected rows along with the rows of the `RETURNING` clause while `returning` is set
+    async fn execute_batch<T>(&self, client: &Client, data: Vec<T>, statement: &Statement, thread_id: i64) -> Result<(u64, Vec<Row>), Error> where T: Upsert<T> + Clone + Send + 'static {
+        match (self.returning.is_some(), self.execution_mode) {
+            (false, ExecutionMode::MultiRow) => T::upsert(client, data, statement, thread_id).await.map(|rows| (rows, vec![])),
+            (false, ExecutionMode::SingleRowPipelined) => Self::upsert_pipelined(client, data, statement, thread_id).await.map(|rows| (rows, vec![])),
+            (true, ExecutionMode::MultiRow) => T::upsert_returning(client, data, statement, thread_id).await.map(|rows| (rows.len() as u64, rows)),
+            (true, ExecutionMode::SingleRowPipelined) => {
+                let rows = futures::future::try_join_all(data.into_iter().map(|record| T::upsert_returning(client, vec![record], statement, thread_id))).await?;
+                let rows = rows.into_iter().flatten().collect::<Vec<Row>>();
+                Ok((rows.len() as u64, rows))
+            },
+        }
+    }
+
+    /// Forwards the rows returned by a written batch to the `returning` channel, waiting while it is full
+    async fn forward_returned(&self, returned: Vec<Row>, n: usize, thread_id: i64) {
+        if let Some(returning) = &self.returning {
+            if !returned.is_empty() && returning.send(returned).await.is_err() {
+                warn!("{}:{}:{}: returning channel is closed, dropping the returned rows", self.name, n, thread_id);
+            }
+        }
+    }
+
+    /// Writes the batch with both statements in one transaction, see `QuickStreamBuilder::dual_write_queries`. Returns the rows of `statement`.
+    async fn upsert_dual_write<T>(&self, client: &Client, data: Vec<T>, statement: &Statement, dual_write_statement: &Statement, thread_id: i64) -> Result<(u64, Vec<Row>), Error> where T: Upsert<T> + Clone + Send + 'static {
+        client.batch_execute("BEGIN").await?;
+        let result = match self.execute_batch(client, data.clone(), statement, thread_id).await {
+            Ok(rows) => self.execute_batch(client, data, dual_write_statement, thread_id).await.map(|_| rows),
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(rows) => {
+                client.batch_execute("COMMIT").await?;
+                Ok(rows)
+            },
+            Err(error) => {
+                if let Err(rollback_error) = client.batch_execute("ROLLBACK").await {
+                    warn!("{}:{}: failed to roll back dual write : {}", self.name, thread_id, rollback_error);
+                }
+                Err(error)
+            },
+        }
+    }
+
+    /// Executes the single row statement for every record at once on the same client, which pipelines them over its connection
+    async fn upsert_pipelined<T>(client: &Client, data: Vec<T>, statement: &Statement, thread_id: i64) -> Result<u64, Error> where T: Upsert<T> + Clone + Send + 'static {
+        let rows = futures::future::try_join_all(data.into_iter().map(|record| T::upsert(client, vec![record], statement, thread_id))).await?;
+        Ok(rows.into_iter().sum())
+    }
+
+    fn upsert_error(&self, error: Error, n: usize, thread_id: i64) -> QuickStreamError {
+        match error.code() {
+            Some(code) if *code == SqlState::CARDINALITY_VIOLATION => {
+                error!("{}:{}:{}: the batch has several rows with the same conflict target although every conflict key is unique within it. Upsert::conflict_key does not match the ON CONFLICT target of the query, error : {}", self.name, n, thread_id, error);
+                QuickStreamError::DuplicateConflictTarget { tier: n, source: error }
+            },
+            _ => QuickStreamError::Database(error),
+        }
+    }
+
+    fn observe_batch_duration(&self, n: usize, thread_id: i64, batch_size: usize, duration: Duration) {
+        if let Some(threshold) = self.slow_batch_threshold {
+            if duration > threshold {
+                warn!("{}:{}:{}: slow batch, upserting {} records took {:?} which exceeds the slow batch threshold {:?}", self.name, n, thread_id, batch_size, duration, threshold);
+                self.slow_batch_count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        #[cfg(feature = "latency-histogram")]
+        self.batch_latencies.lock().unwrap().record(n, duration);
+    }
+
+    /// Latency percentiles of the batches written so far, per tier. Shared between all clones of the stream, so it covers every data ingestor.
+    #[cfg(feature = "latency-histogram")]
+    pub fn batch_latencies(&self) -> HashMap<usize, LatencySnapshot> {
+        self.batch_latencies.lock().unwrap().snapshot()
+    }
+
+    /**
+     The senders of every tier as of the last payload received, e.g. for an admin endpoint diagnosing how the stream scales.
+     Serializable with the `serde` feature. Shared between all clones of the stream.
+     * Taken before the senders are rebalanced, so senders whose data ingestor stopped show up once more with their error
+     * `None` until the stream received its first payload, and with `strict_fifo`
+     */
+    pub fn sender_topology(&self) -> Option<SenderTopology> {
+        self.sender_topology.lock().unwrap().clone()
+    }
+
+    fn record_sender_topology<T>(&self, senders: &HashMap<usize, Vec<UpsertData<T>>>) where T: Upsert<T> + Clone + Send + 'static {
+        let now = self.clock().now();
+        let tiers = self.tiers().into_iter().map(|(tier, _)| TierSnapshot {
+            tier,
+            senders: senders.get(&tier).into_iter().flatten().map(|sender| {
+                let in_flight_records = sender.in_flight.records.load(Ordering::Acquire);
+                let progressed_at = sender.in_flight.progressed_at.lock().unwrap().unwrap_or(sender.created_at);
+                SenderSnapshot {
+                    id: sender.id,
+                    capacity: sender.tx.capacity() as f64 / self.buffer_size as f64 * 100f64,
+                    in_flight_records,
+                    idle_millis: (in_flight_records == 0).then(|| now.saturating_duration_since(progressed_at).as_millis() as u64),
+                    last_error: sender.in_flight.last_error.lock().unwrap().clone(),
+                }
+            }).collect(),
+        }).collect::<Vec<TierSnapshot>>();
+
+        *self.sender_topology.lock().unwrap() = Some(SenderTopology {
+            name: self.name.to_owned(),
+            total_senders: tiers.iter().map(|tier| tier.senders.len()).sum(),
+            max_connection_count: self.max_con_count(),
+            tiers,
+        });
+    }
+
+    /// Number of times a data ingestor stalled, see `QuickStreamBuilder::stalled_ingestor_threshold`.
+    /// Shared between all clones of the stream, so it covers every data ingestor.
+    pub fn stalled_ingestor_count(&self) -> u64 {
+        self.stalled_ingestor_count.load(Ordering::Relaxed)
+    }
+
+    /**
+     Counts and logs the data ingestors which have batches in flight but wrote none within the stalled ingestor threshold,
+     once per stall. With `recreate_stalled_ingestors` their tasks are aborted and their senders removed, returning how many.
+     */
+    fn remove_stalled_senders<T>(&self, senders: &mut Vec<UpsertData<T>>) -> usize where T: Upsert<T> + Clone + Send + 'static {
+        let stalled_ingestor_threshold = match self.stalled_ingestor_threshold {
+            Some(stalled_ingestor_threshold) => stalled_ingestor_threshold,
+            None => return 0,
+        };
+
+        let start_senders = senders.len();
+        let now = self.clock().now();
+        senders.retain(|sender| {
+            let records = sender.in_flight.records.load(Ordering::Acquire);
+            let progressed_at = sender.in_flight.progressed_at.lock().unwrap().unwrap_or(sender.created_at);
+            if records == 0 || now.saturating_duration_since(progressed_at) < stalled_ingestor_threshold {
+                return true
+            }
+
+            if !sender.in_flight.stalled.swap(true, Ordering::AcqRel) {
+                warn!("{}: data ingestor {}:{} stalled, no batch written for {:?} with {} records in flight", self.name, sender.type_, sender.id, now.saturating_duration_since(progressed_at), records);
+                self.stalled_ingestor_count.fetch_add(1, Ordering::Relaxed);
+            }
+            if !self.recreate_stalled_ingestors {
+                return true
+            }
+
+            // aborting drops its connection, the batches queued to it are not written. the data ingestor may still release
+            // a batch it finished while being aborted, so only the records left are released
+            sender.join_handler.abort();
+            let records = self.release_all_in_flight(&sender.in_flight);
+            warn!("{}: recreated stalled data ingestor {}:{}, dropped {} records in flight", self.name, sender.type_, sender.id, records);
+            self.fail_batches(&sender.in_flight);
+            false
+        });
+        start_senders - senders.len()
+    }
+
+    /// Number of batches whose upsert exceeded the configured `slow_batch_threshold`.
+    /// Shared between all clones of the stream, so it covers every data ingestor.
+    pub fn slow_batch_count(&self) -> u64 {
+        self.slow_batch_count.load(Ordering::Relaxed)
+    }
+
+    /// Removes duplicates of an oversized payload and splits it into chunks of `max_payload_records`, returning the full chunks
+    /// and the remainder. Duplicates are removed across the whole payload so no primary key is written by two chunks.
+    fn chunk_payload<T>(&self, mut data: Vec<T>, max_payload_records: usize) -> (Vec<Vec<T>>, Vec<T>) where T: Upsert<T> + Clone + Send + 'static {
+        warn!("{}: payload of {} records exceeds max payload records {}, ingesting it in chunks", self.name, data.len(), max_payload_records);
+
+        remove_duplicates(&mut data);
+        let mut chunks = vec![];
+        while data.len() > max_payload_records {
+            let remainder = data.split_off(max_payload_records);
+            chunks.push(data);
+            data = remainder;
+        }
+
+        (chunks, data)
+    }
+
+    /// Number of rows accepted for ingestion, after removing duplicates. Counted against `max_total_rows` if set.
+    pub fn total_rows(&self) -> u64 {
+        self.total_rows.load(Ordering::Relaxed)
+    }
+
+    /// Counts the given rows against `max_total_rows`, discarding the rows beyond it. Returns true when the cap is reached.
+    fn take_quota<T>(&self, data: &mut Vec<T>) -> bool {
+        match self.max_total_rows {
+            Some(max_total_rows) => {
+                let remaining = max_total_rows.saturating_sub(self.total_rows()) as usize;
+                if data.len() > remaining {
+                    warn!("{}: max total rows {} reached, discarding {} rows", self.name, max_total_rows, data.len() - remaining);
+                    data.truncate(remaining);
+                }
+                self.total_rows.fetch_add(data.len() as u64, Ordering::Relaxed);
+                self.total_rows() >= max_total_rows
+            },
+            None => {
+                self.total_rows.fetch_add(data.len() as u64, Ordering::Relaxed);
+                false
+            },
+        }
+    }
+
+    /// The latest `modified_date` written by the data ingestors, see `QuickStreamBuilder::watermark_query`
+    pub fn written_watermark(&self) -> Option<NaiveDateTime> {
+        *self.written_watermark.lock().unwrap()
+    }
+
+    fn observe_watermark(&self, max_modified_date: Option<NaiveDateTime>, written: bool) {
+        if !written {
+            if self.watermark_query.is_some() && !self.watermark_frozen.swap(true, Ordering::AcqRel) {
+                error!("{}: a batch failed to be written, the watermark will not advance anymore", self.name);
+            }
+            return
+        }
+
+        if let Some(max_modified_date) = max_modified_date {
+            let mut written_watermark = self.written_watermark.lock().unwrap();
+            if *written_watermark < Some(max_modified_date) {
+                *written_watermark = Some(max_modified_date);
+            }
+        }
+    }
+
+    /// The watermark which is safe to persist, only while every record pushed to the data ingestors is written
+    fn durable_watermark(&self) -> Option<NaiveDateTime> {
+        if self.watermark_frozen.load(Ordering::Acquire) || self.in_flight_records() > 0 {
+            return None
+        }
+        self.written_watermark()
+    }
+
+    async fn write_watermarks(&self, watermark_query: String, watermark_interval: Duration, cancellation_token: CancellationToken) -> Result<(), QuickStreamError> {
+        let client = self.get_db_client().await?;
+        let statement = client.prepare(watermark_query.as_str()).await?;
+
+        let mut last_written = None;
+        loop {
+            tokio::select! {
+                _ = cancellation_token.cancelled() => break,
+                _ = self.clock().sleep(watermark_interval) => {}
+            }
+
+            match self.durable_watermark() {
+                Some(watermark) if last_written != Some(watermark) => {
+                    trace!("{}: writing watermark {}", self.name, watermark);
+                    client.execute(&statement, &[&self.name, &watermark]).await?;
+                    last_written = Some(watermark);
+                },
+                _ => trace!("{}: no new durable watermark to write", self.name),
+            }
+        }
+
+        info!("{}: watermark writer stopped", self.name);
+        Ok(())
+    }
+
+    /// Number of records pushed to data ingestors which are not written yet, across all connections.
+    pub fn in_flight_records(&self) -> usize {
+        self.in_flight_records.load(Ordering::Relaxed)
+    }
+
+    fn acquire_in_flight(&self, in_flight: &InFlight, amount: usize) {
+        // an idle data ingestor starts its stall timer with its first batch
+        if in_flight.records.fetch_add(amount, Ordering::AcqRel) == 0 {
+            in_flight.progress(self.clock().now());
+        }
+        in_flight.permits.fetch_add(self.in_flight_permit_count(amount) as usize, Ordering::AcqRel);
+        self.in_flight_records.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Releases the records of a batch. Never releases more than the data ingestor holds, as the records left may have been released already
+    /// while it was recreated, see `release_all_in_flight`
+    fn release_in_flight(&self, in_flight: &InFlight, amount: usize) {
+        let records = take_saturating(&in_flight.records, amount);
+        let permits = take_saturating(&in_flight.permits, self.in_flight_permit_count(amount) as usize);
+        self.give_back_in_flight(in_flight, records, permits);
+    }
+
+    /// Releases every record the data ingestor holds, returning their number
+    fn release_all_in_flight(&self, in_flight: &InFlight) -> usize {
+        let records = in_flight.records.swap(0, Ordering::AcqRel);
+        let permits = in_flight.permits.swap(0, Ordering::AcqRel);
+        self.give_back_in_flight(in_flight, records, permits);
+        records
+    }
+
+    fn give_back_in_flight(&self, in_flight: &InFlight, records: usize, permits: usize) {
+        self.in_flight_records.fetch_sub(records, Ordering::Relaxed);
+        if let Some(in_flight_permits) = &self.in_flight_permits {
+            in_flight_permits.add_permits(permits);
+        }
+        in_flight.released.notify_one();
+    }
+
+    /// Permits taken for a batch, at most every permit so a batch larger than `max_in_flight_records` still gets through
+    fn in_flight_permit_count(&self, amount: usize) -> u32 {
+        amount.min(self.max_in_flight_records.unwrap_or(usize::MAX)).min(Semaphore::MAX_PERMITS).min(u32::MAX as usize) as u32
+    }
+
+    /// Waits until the stream has room for the given amount of records within `max_in_flight_records`, across every connection.
+    /// The permits are given back by `release_in_flight`.
+    async fn wait_for_in_flight_permits(&self, amount: usize) {
+        if let Some(in_flight_permits) = &self.in_flight_permits {
+            trace!("{}: waiting for room for {} records within max in flight records, {} available", self.name, amount, in_flight_permits.available_permits());
+            if let Ok(permits) = in_flight_permits.acquire_many(self.in_flight_permit_count(amount)).await {
+                permits.forget();
+            }
+        }
+    }
+
+    /// Numbers the next batch dispatched, opening its span as a child of the span of its payload
+    fn next_batch(&self, rows: usize, #[cfg(feature = "tracing")] payload_span: &tracing::Span) -> PushedBatch {
+        let sequence = self.confirmation_sequencer().next_sequence();
+        PushedBatch {
+            sequence,
+            rows,
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(parent: payload_span, "quick_stream.batch", stream = %self.name, rows, sequence, sender = tracing::field::Empty, written = tracing::field::Empty),
+        }
+    }
+
+    /// Span of writing the oldest batches pushed to the data ingestor, a child of the span of the first of them
+    #[cfg(feature = "tracing")]
+    fn upsert_span(&self, in_flight: &InFlight, tier: usize, thread_id: i64, batches: usize) -> tracing::Span {
+        match in_flight.batches.lock().unwrap().front() {
+            Some(pushed_batch) => tracing::info_span!(parent: &pushed_batch.span, "quick_stream.upsert", stream = %self.name, tier, sender = thread_id, batches),
+            None => tracing::Span::none(),
+        }
+    }
+
+    /// Confirms a written or failed batch, closing its span
+    fn settle_batch(&self, pushed_batch: PushedBatch, written: bool) {
+        #[cfg(feature = "tracing")]
+        pushed_batch.span.record("written", written);
+        self.confirm_batch(pushed_batch.sequence, pushed_batch.rows, written);
+    }
+
+    fn confirmation_sequencer(&self) -> MutexGuard<'_, ConfirmationSequencer> {
+        // a panicking hook must not stop every data ingestor confirming after it
+        self.confirmation_sequencer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Calls the confirmation hook for every batch confirmed in order by the given settled batch. The hook is called
+    /// while holding the sequencer, so confirmations of data ingestors finishing at the same time are not interleaved.
+    fn confirm_batch(&self, sequence: u64, rows: usize, written: bool) {
+        let mut confirmation_sequencer = self.confirmation_sequencer();
+        let confirmations = confirmation_sequencer.confirm(sequence, rows, written);
+        if let Some(confirmation_hook) = &self.confirmation_hook {
+            for confirmation in confirmations {
+                confirmation_hook(confirmation);
+            }
+        }
+        trace!("{}: {} settled batches held back for ordered confirmation", self.name, confirmation_sequencer.held_back());
+    }
+
+    /// Confirms the oldest batch pushed to the data ingestor as written
+    fn confirm_next_batch(&self, in_flight: &InFlight) {
+        let next = in_flight.batches.lock().unwrap().pop_front();
+        if let Some(pushed_batch) = next {
+            self.settle_batch(pushed_batch, true);
+        }
+    }
+
+    /// Confirms every batch pushed to a data ingestor which stopped or was aborted as failed, so later batches are not held back by them
+    fn fail_batches(&self, in_flight: &InFlight) {
+        let failed = in_flight.batches.lock().unwrap().drain(..).collect::<Vec<PushedBatch>>();
+        for pushed_batch in failed {
+            self.settle_batch(pushed_batch, false);
+        }
+    }
+
+    /**
+     Writes every payload pushed to the main channel before the call right away, regardless of the batch thresholds and the lag cycles,
+     and waits until every batch dispatched before is written. For transactional checkpoints of a producer, the stream keeps running afterwards.
+     * Fails with `FlushFailed` when a batch dispatched since the previous flush failed, its rows are then not written
+     * Waits for the next `run` while the stream is not running. To flush without waiting use `request_flush`
+     */
+    pub async fn flush(&self) -> Result<(), QuickStreamError> {
+        let (flushed_tx, flushed) = oneshot::channel();
+        self.confirmation_sequencer().request_flush(Some(flushed_tx));
+        self.flush_requested.notify_one();
+        // the sequencer holding the sender lives as long as the stream
+        let failed_batches = flushed.await.unwrap_or(0);
+        match failed_batches {
+            0 => Ok(()),
+            failed_batches => Err(QuickStreamError::FlushFailed { failed_batches }),
+        }
+    }
+
+    /// Writes every payload pushed to the main channel before the call right away like `flush`, without waiting for the batches to be written
+    pub fn request_flush(&self) {
+        self.confirmation_sequencer().request_flush(None);
+        self.flush_requested.notify_one();
+    }
+
+    /// Removes the payloads queued in the main channel when a flush was requested, later payloads are left in the channel
+    fn take_queued<T>(&self, rx: &mut Receiver<Vec<T>>) -> Vec<T> where T: Upsert<T> + Clone + Send + 'static {
+        let queued = rx.len();
+        trace!("{}: flush requested, taking {} queued payloads", self.name, queued);
+        (0..queued).map_while(|_| rx.try_recv().ok()).flatten().collect()
+    }
+
+    /// Places the barrier of the requested flushes after the batches dispatched so far
+    fn place_flush_barriers(&self) {
+        trace!("{}: flushed payloads pushed for ingestion", self.name);
+        self.confirmation_sequencer().place_flush_barriers();
+    }
+
+    /**
+     Waits until the connection has room for the given amount of records within `max_in_flight_records_per_connection`.
+     * A batch larger than the budget is let through once the connection has nothing in flight, otherwise it would wait forever.
+     */
+    async fn wait_for_in_flight_budget(&self, in_flight: &InFlight, amount: usize) {
+        if let Some(budget) = self.max_in_flight_records_per_connection {
+            loop {
+                let records = in_flight.records.load(Ordering::Acquire);
+                if records == 0 || records + amount <= budget {
+                    break;
+                }
+                trace!("{}: {} records in flight, waiting for room for {} more within the budget of {}", self.name, records, amount, budget);
+                in_flight.released.notified().await;
+            }
+        }
+    }
+
+    /**
+     Sender types paired with the amount of senders initially created for them (the minimum kept while rebalancing).
+     A sender type is the amount of records in the batches it ingests.
+     */
+    pub(crate) fn tiers(&self) -> Vec<(usize, usize)> {
+        let mut tiers = (1..10).map(|n| (n, self.single_digits)).collect::<Vec<(usize, usize)>>();
+        tiers.push((10, self.tens));
+        if let Some(fifties) = self.fifties {
+            tiers.push((50, fifties));
+        }
+        tiers.push((100, self.hundreds));
+        tiers
+    }
+
+    /// Splits the records into batches of the tiers
+    fn split<T>(&self, data: Vec<T>) -> Vec<Vec<T>> where T: Upsert<T> + Clone + Send + 'static {
+        match self.fifties {
+            Some(_) => split_vec_with_fifties(data),
+            None => split_vec(data),
+        }
+    }
+
+    /// Like `split`, splitting every statement variant on its own when `variant_queries` are set so no batch mixes variants
+    fn split_by_variant<T>(&self, data: Vec<T>) -> Vec<Vec<T>> where T: Upsert<T> + Clone + Send + 'static {
+        if self.variant_queries.is_empty() {
+            return self.split(data)
+        }
+
+        group_by_variant(data).into_iter().flat_map(|group| self.split(group)).collect()
+    }
+
+    fn init_limit(&self, type_: usize) -> Option<usize> {
+        self.tiers().into_iter().find(|(tier, _)| *tier == type_).map(|(_, init_limit)| init_limit)
+    }
+
+    fn init_sender<T>(&self, count: usize, tx_count: &mut i64, type_: usize) -> Vec<UpsertData<T>> where T: Upsert<T> + Clone + Send + 'static {
+        trace!("{}: initiating sender, creating {} upsert senders", self.name, count);
+        let mut senders = vec![];
+    
+        for _ in 0..count {
+            let (tx_t, rx_t) = mpsc::channel::<Vec<T>>(self.buffer_size);
+    
+            let thread_id = tx_count.clone();
+            let in_flight = Arc::new(InFlight::default());
+            let in_flight_clone = in_flight.clone();
+            let (ready_tx, ready_rx) = match self.strict_readiness {
+                true => {
+                    let (ready_tx, ready_rx) = oneshot::channel();
+                    (Some(ready_tx), Some(ready_rx))
+                },
+                false => (None, None),
+            };
+            let self_clone = self.to_owned();
+            let handler = self.spawn(async move {
+                if let Err(error) = self_clone.process_n(rx_t, thread_id, type_, in_flight_clone, ready_tx).await {
+                    error!("{}:{}:{}: data ingestor stopped with error : {}", self_clone.name, type_, thread_id, error);
+                }
+                1u8
+            });
+    
+            let mut tx_struct = UpsertData::new(tx_t, handler, tx_count.clone(), type_, in_flight, self.clock().now());
+            tx_struct.ready = ready_rx;
+    
+            *tx_count += 1;
+    
+            senders.push(tx_struct);
+        }
+    
+        senders
+    }
+
+    fn init_senders<T>(&self, tx_count: &mut i64) -> HashMap<usize, Vec<UpsertData<T>>> where T: Upsert<T> + Clone + Send + 'static {
+        let tiers = self.tiers();
+        trace!("{}: creating sender map of capacity {}", self.name, tiers.len());
+        let mut sender_map = HashMap::with_capacity(tiers.len());
+
+        for (type_, count) in tiers {
+            trace!("{}: creating data senders for {}", self.name, type_);
+            let senders = self.init_sender::<T>(count, tx_count, type_);
+            sender_map.insert(type_, senders);
+            trace!("{}: creating data senders for {} success", self.name, type_);
+        }
+
+        self.print_sender_status(&sender_map, &tx_count);
+
+        sender_map
+    }
+
+    /**
+     Waits until every data ingestor created by `init_senders` connected and prepared its statements.
+     * Fails with the first error of every failed tier, as the data ingestors of a tier share the same queries
+     */
+    async fn wait_for_readiness<T>(&self, senders: &mut HashMap<usize, Vec<UpsertData<T>>>) -> Result<(), QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        let mut errors = HashMap::new();
+        for sender in senders.values_mut().flatten() {
+            if let Some(ready) = sender.ready.take() {
+                let error = match ready.await {
+                    Ok(Ok(())) => {
+                        trace!("{}: data ingestor {}:{} is ready", self.name, sender.type_, sender.id);
+                        continue;
+                    },
+                    Ok(Err(error)) => {
+                        error!("{}: data ingestor {}:{} failed to get ready, error : {}", self.name, sender.type_, sender.id, error);
+                        error
+                    },
+                    Err(_) => {
+                        error!("{}: data ingestor {}:{} stopped before it was ready", self.name, sender.type_, sender.id);
+                        QuickStreamError::IngestorStopped { tier: sender.type_, id: sender.id }
+                    },
+                };
+                errors.entry(sender.type_).or_insert(error);
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(())
+        }
+        let mut errors = errors.into_iter().collect::<Vec<(usize, QuickStreamError)>>();
+        errors.sort_by_key(|(tier, _)| *tier);
+        Err(QuickStreamError::NotReady(errors.into_iter().map(|(_, error)| error).collect()))
+    }
+
+    async fn push_to_handle<T>(&self, senders: &mut HashMap<usize, Vec<UpsertData<T>>>, vec_data: Vec<Vec<T>>, tx_count: &mut i64) -> Result<(), QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        #[cfg(feature = "tracing")]
+        let payload_span = tracing::info_span!("quick_stream.payload", stream = %self.name, records = vec_data.iter().map(Vec::len).sum::<usize>(), batches = vec_data.len());
+        for data in vec_data {
+            if data.is_empty() {
+                trace!("{}: skipping empty batch", self.name);
+                continue;
+            }
+
+            let k = data.len();
+            let pushed_batch = self.next_batch(k, #[cfg(feature = "tracing")] &payload_span);
+            let Some(tier_senders) = senders.get_mut(&k) else {
+                error!("{}: no senders of tier {}, check quick_stream::split_vec<T>(data: Vec<T>) function", self.name, k);
+                self.settle_batch(pushed_batch, false);
+                return Err(QuickStreamError::MissingTier { tier: k })
+            };
+            self.handle_n(data, tier_senders, tx_count, k, pushed_batch).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_n<T>(&self, data: Vec<T>, senders: &mut Vec<UpsertData<T>>, tx_count: &mut i64, type_: usize, pushed_batch: PushedBatch) -> Result<(), QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        trace!("{}: handeling data started", self.name);
+        self.poll_connecting_senders(senders, tx_count, type_);
+        trace!("{}: sorting senders by capacity to get the channel with highest capacity", self.name);
+        // senders still connecting go last, they only get batches once no connected sender is open
+        senders.sort_by(|x, y| (x.ready.is_some(), y.tx.capacity()).cmp(&(y.ready.is_some(), x.tx.capacity())));
+
+        // a tier keeps at least one sender, see re_balance_sender
+        let Some(sender_0) = senders.first() else {
+            error!("{}: no senders of type {} found, failed to send data", self.name, type_);
+            self.settle_batch(pushed_batch, false);
+            return Err(QuickStreamError::SendersClosed { tier: type_ })
+        };
+
+        let capacity = sender_0.tx.capacity() as f64 / self.buffer_size as f64 * 100f64;
+
+        let connection_creation_threshold = self.effective_connection_creation_threshold();
+        if capacity <= connection_creation_threshold {
+            warn!("{}: capacity of {}:{} {}% is below connection creation threshold {}%", self.name, sender_0.type_, sender_0.id, capacity, connection_creation_threshold);
+
+            if senders.iter().any(|sender| sender.ready.is_some()) {
+                info!("{}: a sender of type {} is still connecting, waiting for capacity of the existing senders", self.name, type_);
+                self.send_to_available_sender(senders, data, type_, pushed_batch).await?;
+            } else if *tx_count < self.max_con_count() as i64 && !self.scale_up_paused() && self.take_connection_creation_token() {
+                info!("{}: creating a sender of type {} since current connections {} is below allowed max connections count {}", self.name, type_, *tx_count, self.max_con_count());
+                let (tx_t, rx_t) = mpsc::channel::<Vec<T>>(self.buffer_size);
+
+                let thread_id = *tx_count;
+                let in_flight = Arc::new(InFlight::default());
+                let in_flight_clone = in_flight.clone();
+                let (ready_tx, ready_rx) = oneshot::channel();
+                let self_clone = Arc::new(self.to_owned());
+                let handler = self.spawn(async move {
+                    if let Err(error) = self_clone.process_n(rx_t, thread_id, type_, in_flight_clone, Some(ready_tx)).await {
+                        error!("{}:{}:{}: data ingestor stopped with error : {}", self_clone.name, type_, thread_id, error);
+                    }
+                    0u8
+                });
+
+                // batches are only queued to the new sender once its data ingestor connected, see poll_connecting_senders,
+                // as a failed connection would drop them along with its channel
+                let mut tx_struct = UpsertData::new(tx_t, handler, thread_id, type_, in_flight, self.clock().now());
+                tx_struct.ready = Some(ready_rx);
+                info!("{}: creating sender {}:{}, sending through the existing senders while it connects", self.name, tx_struct.type_, tx_struct.id);
+                *tx_count += 1;
+                senders.push(tx_struct);
+                self.notify_rebalance(type_, 1, 0, RebalanceReason::CapacityBelowThreshold);
+                *self.last_scale_up.lock().unwrap() = Some(self.clock().now());
+                self.record_scale_up(self.clock().now());
+
+                if *tx_count == self.max_con_count() as i64 {
+                    log!(self.status_log_level(), "{}: max connection count reached", self.name)
+                } else {
+                    log!(self.status_log_level(), "{}: connection created, current total connections : {}", self.name, tx_count)
+                }
+                self.send_to_available_sender(senders, data, type_, pushed_batch).await?;
+            } else if *tx_count < self.max_con_count() as i64 && self.scale_up_paused() {
+                warn!("{}: scaling up is paused as the database refused connections, waiting for capacity of the existing senders of type {}", self.name, type_);
+                self.send_to_available_sender(senders, data, type_, pushed_batch).await?;
+            } else if *tx_count < self.max_con_count() as i64 {
+                warn!("{}: connection creation rate limit reached, waiting for capacity of the existing senders of type {}", self.name, type_);
+                self.send_to_available_sender(senders, data, type_, pushed_batch).await?;
+            } else {
+                error!("{}: unable to create connection as max connection count has already reached", self.name);
+                warn!("{}: PROCESSOR WILL HAVE TO WAIT UNTIL CAPACITY IS AVAIALABLE TO PROCEED", self.name);
+                self.send_to_available_sender(senders, data, type_, pushed_batch).await?;
+                info!("{}: data successfully pushed after capacity was available", self.name);
+            }
+        } else {
+            log!(self.status_log_level(), "{}: capacity of sender {}:{} is at {}%", self.name, sender_0.type_, sender_0.id, capacity);
+            if self.sender_selection == SenderSelection::WeightedRandom {
+                self.pick_weighted_random_sender(senders, connection_creation_threshold, self.next_random());
+            }
+            self.send_to_available_sender(senders, data, type_, pushed_batch).await?;
+        }
+        Ok(())
+    }
+
+    /// A random number, or the next number of the fixed sequence shared by every clone of the stream while `deterministic`
+    fn next_random(&self) -> u64 {
+        match self.deterministic {
+            true => splitmix64(&self.random_state),
+            false => random_u64(),
+        }
+    }
+
+    /// Moves a random sender above the connection creation threshold to the front, weighted by its free capacity. Expects the senders sorted by capacity.
+    fn pick_weighted_random_sender<T>(&self, senders: &mut [UpsertData<T>], connection_creation_threshold: f64, random: u64) where T: Upsert<T> + Clone + Send + 'static {
+        let weights = senders.iter()
+            .map(|sender| match sender.ready.is_none() && sender.tx.capacity() as f64 / self.buffer_size as f64 * 100f64 > connection_creation_threshold {
+                true => sender.tx.capacity(),
+                false => 0,
+            })
+            .collect::<Vec<usize>>();
+
+        if let Some(index) = weighted_random_index(&weights, random) {
+            trace!("{}: picked sender {}:{} at random", self.name, senders[index].type_, senders[index].id);
+            // the others keep their order, so a closed pick still falls back to the highest capacity
+            senders[..=index].rotate_right(1);
+        }
+    }
+
+    /**
+     Hands batches to the senders whose data ingestor got ready, and removes those which failed to get ready
+     or are not ready within the connect timeout, at least `SCALE_UP_READINESS_TIMEOUT`.
+     * Senders still connecting are only sent to once no connected sender of their tier is open, see `handle_n`
+     */
+    fn poll_connecting_senders<T>(&self, senders: &mut Vec<UpsertData<T>>, tx_count: &mut i64, type_: usize) where T: Upsert<T> + Clone + Send + 'static {
+        let now = self.clock().now();
+        let readiness_timeout = self.connect_timeout.map_or(SCALE_UP_READINESS_TIMEOUT, |connect_timeout| connect_timeout.max(SCALE_UP_READINESS_TIMEOUT));
+        let start_senders = senders.len();
+        senders.retain_mut(|sender| {
+            let Some(ready) = &mut sender.ready else {
+                return true
+            };
+            match ready.try_recv() {
+                Ok(Ok(())) => {
+                    info!("{}: data ingestor {}:{} is ready, sending batches to it", self.name, sender.type_, sender.id);
+                    sender.ready = None;
+                    true
+                },
+                Ok(Err(error)) => {
+                    // the data ingestor failed the batches it got itself
+                    warn!("{}: data ingestor {}:{} failed to get ready, removing its sender, error : {}", self.name, sender.type_, sender.id, error);
+                    false
+                },
+                Err(oneshot::error::TryRecvError::Closed) => {
+                    warn!("{}: data ingestor {}:{} stopped before it was ready, removing its sender", self.name, sender.type_, sender.id);
+                    self.release_all_in_flight(&sender.in_flight);
+                    self.fail_batches(&sender.in_flight);
+                    false
+                },
+                Err(oneshot::error::TryRecvError::Empty) if now.saturating_duration_since(sender.created_at) >= readiness_timeout => {
+                    warn!("{}: data ingestor {}:{} is not ready after {:?}, aborting it", self.name, sender.type_, sender.id, readiness_timeout);
+                    sender.join_handler.abort();
+                    self.release_all_in_flight(&sender.in_flight);
+                    self.fail_batches(&sender.in_flight);
+                    false
+                },
+                Err(oneshot::error::TryRecvError::Empty) => true,
+            }
+        });
+
+        let removed_senders = start_senders - senders.len();
+        if removed_senders > 0 {
+            *tx_count -= removed_senders as i64;
+            self.notify_rebalance(type_, 0, removed_senders, RebalanceReason::ClosedSenders);
+        }
+    }
+
+    /// Whether a sender may be created to scale up within `max_connection_creation_rate`
+    fn take_connection_creation_token(&self) -> bool {
+        match &self.connection_creation_limiter {
+            Some(connection_creation_limiter) => connection_creation_limiter.lock().unwrap().try_take(self.clock().now()),
+            None => true,
+        }
+    }
+
+    /// Stops creating senders to scale up for `too_many_connections_cooldown`, the stream continues with its existing senders
+    fn pause_scale_up(&self, thread_id: i64, n: usize) {
+        let now = self.clock().now();
+        warn!("{}:{}:{}: database refused the connection as it has too many clients, pausing scale up for {:?}", self.name, n, thread_id, self.too_many_connections_cooldown);
+        *self.scale_up_paused_until.lock().unwrap() = Some(now + self.too_many_connections_cooldown);
+        // connections resume at the configured rate once the pause is over, instead of in a burst
+        if let Some(connection_creation_limiter) = &self.connection_creation_limiter {
+            connection_creation_limiter.lock().unwrap().drain(now);
+        }
+    }
+
+    /// Whether scaling up is paused after the database refused a connection
+    fn scale_up_paused(&self) -> bool {
+        self.scale_up_paused_until.lock().unwrap().is_some_and(|paused_until| self.clock().now() < paused_until)
+    }
+
+    fn record_scale_up(&self, now: Instant) {
+        let mut scale_ups = self.scale_ups.lock().unwrap();
+        scale_ups.push_back(now);
+        while scale_ups.front().is_some_and(|scale_up| now.saturating_duration_since(*scale_up) > SCALE_UP_RATE_WINDOW) {
+            scale_ups.pop_front();
+        }
+    }
+
+    /// Source of time of the stream, see `QuickStreamBuilder::clock`
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        self.clock.as_deref().unwrap_or(&TokioClock)
+    }
+
+    /// Senders created per second to scale up, averaged over the last 10 seconds. Shared between all clones of the stream.
+    pub fn connection_creation_rate(&self) -> f64 {
+        let now = self.clock().now();
+        let scale_ups = self.scale_ups.lock().unwrap();
+        let recent = scale_ups.iter().filter(|scale_up| now.saturating_duration_since(**scale_up) <= SCALE_UP_RATE_WINDOW).count();
+        recent as f64 / SCALE_UP_RATE_WINDOW.as_secs_f64()
+    }
+
+    /// Sends the data through the first open sender, in the given order. A data ingestor may stop between choosing its sender
+    /// and sending to it, in which case the data is sent through the next sender instead.
+    async fn send_to_available_sender<T>(&self, senders: &[UpsertData<T>], mut data: Vec<T>, type_: usize, pushed_batch: PushedBatch) -> Result<(), QuickStreamError> where T: Upsert<T> + Clone + Send + 'static {
+        for sender in senders {
+            let amount = data.len();
+            self.wait_for_in_flight_budget(&sender.in_flight, amount).await;
+            self.wait_for_in_flight_permits(amount).await;
+            self.acquire_in_flight(&sender.in_flight, amount);
+            #[cfg(feature = "tracing")]
+            pushed_batch.span.record("sender", sender.id);
+            sender.in_flight.batches.lock().unwrap().push_back(pushed_batch.clone());
+            self.notify_dispatch(sender.type_, sender.id, amount);
+            match sender.tx.send(data).await {
+                Ok(_) => {
+                    trace!("{}: pushing to data ingestor success using sender {}:{}", self.name, sender.type_, sender.id);
+                    return Ok(());
+                },
+                Err(error) => {
+                    self.release_in_flight(&sender.in_flight, amount);
+                    let mut batches = sender.in_flight.batches.lock().unwrap();
+                    // already failed if the data ingestor stopped in between, the next sender then writes it regardless
+                    if batches.back().is_some_and(|batch| batch.sequence == pushed_batch.sequence) {
+                        batches.pop_back();
+                    }
+                    drop(batches);
+                    warn!("{}: sender {}:{} is closed, retrying with the next sender", self.name, sender.type_, sender.id);
+                    data = error.0;
+                },
+            }
+        }
+
+        error!("{}: all senders of type {} are closed, failed to send data", self.name, type_);
+        self.settle_batch(pushed_batch, false);
+        Err(QuickStreamError::SendersClosed { tier: type_ })
+    }
+
+    fn re_balance_sender<T>(&self, senders: &mut Vec<UpsertData<T>>, init_limit: usize, tx_count: &mut i64, type_: usize) -> bool where T: Upsert<T> + Clone + Send + 'static {
+
+        trace!("{}: rebalancing senders of type {}", self.name, type_);
+
+        let start_senders = senders.len();
+        let stalled_senders = self.remove_stalled_senders(senders);
+        if stalled_senders > 0 {
+            *tx_count -= stalled_senders as i64;
+            self.notify_rebalance(type_, 0, stalled_senders, RebalanceReason::StalledSenders);
+        }
+
+        let unstalled_senders = senders.len();
+        senders.retain_mut(|upsert_data| {
+            if !upsert_data.join_handler.is_finished() {
+                return true
+            }
+            // the task is finished, so its result is ready
+            match (&mut upsert_data.join_handler).now_or_never() {
+                Some(Err(error)) if error.is_panic() => {
+                    error!("{}: data ingestor {}:{} panicked : {}", self.name, upsert_data.type_, upsert_data.id, error);
+                },
+                _ => warn!("{}: data ingestor {}:{} stopped", self.name, upsert_data.type_, upsert_data.id),
+            }
+            // left by a panic, a data ingestor stopping on an error releases and fails its batches itself
+            self.release_all_in_flight(&upsert_data.in_flight);
+            self.fail_batches(&upsert_data.in_flight);
+            false
+        });
+        senders.retain(|upsert_data| !upsert_data.tx.is_closed());
+
+        let removed_senders = unstalled_senders - senders.len();
+
+        if removed_senders > 0 {
+            info!("{}: removed {} senders of type {}", self.name, removed_senders, type_);
+            *tx_count -= removed_senders as i64;
+            self.notify_rebalance(type_, 0, removed_senders, RebalanceReason::ClosedSenders);
+        }
+
+        let respawn_count = self.respawn_count(init_limit.saturating_sub(senders.len()), senders.is_empty(), *tx_count, type_);
+        if respawn_count > 0 {
+            warn!("{}: senders of type {} are below the initial amount {}, respawning {} senders", self.name, type_, init_limit, respawn_count);
+            senders.append(&mut self.init_sender(respawn_count, tx_count, type_));
+            self.notify_rebalance(type_, respawn_count, 0, RebalanceReason::BelowTierMinimum);
+        }
+
+        if senders.len() > init_limit {
+            let full_capacity_count = senders.iter().filter(|sender| self.is_removable(sender)).collect::<Vec<&UpsertData<T>>>().len();
+    
+            if full_capacity_count > 0 {
+                let mut amount_to_pop = match *tx_count > self.max_con_count() as i64 {
+                    // above a lowered max connection count every idle sender goes at once
+                    true => full_capacity_count,
+                    false => full_capacity_count - (full_capacity_count / 2usize),
+                };
+                if senders.len() - amount_to_pop < init_limit {
+                    amount_to_pop = senders.len() - init_limit;
+                }
+                // removable senders with the highest capacity go last
+                senders.sort_by(|x, y| (self.is_removable(x), x.tx.capacity()).cmp(&(self.is_removable(y), y.tx.capacity())));
+                for _ in 0..amount_to_pop {
+                    senders.pop();
+                    *tx_count -= 1;
+                }
+                if amount_to_pop > 0 {
+                    self.notify_rebalance(type_, 0, amount_to_pop, RebalanceReason::IdleSenders);
+                }
+            }
+        }
+
+        trace!("{}: rebalancing senders of type {} complete", self.name, type_);
+        senders.len() != start_senders || respawn_count > 0
+    }
+
+    /**
+     Senders to respawn of the missing senders of a tier below its initial amount, within the limits of scaling up.
+     Respawning waits while scaling up is paused and stays within `max_connection_count` and `max_connection_creation_rate`,
+     the rest is respawned with a later rebalance.
+     * A tier left without senders always gets one, as its batches fail with `SendersClosed` otherwise
+     */
+    fn respawn_count(&self, missing: usize, empty: bool, tx_count: i64, type_: usize) -> usize {
+        if missing == 0 {
+            return 0
+        }
+
+        let respawn_count = match self.scale_up_paused() {
+            true => {
+                warn!("{}: scaling up is paused as the database refused connections, deferring respawning {} senders of type {}", self.name, missing, type_);
+                0
+            },
+            false => {
+                let allowed = missing.min((self.max_con_count() as i64 - tx_count).max(0) as usize);
+                let respawn_count = (0..allowed).take_while(|_| self.take_connection_creation_token()).count();
+                if respawn_count < missing {
+                    warn!("{}: max connection count or connection creation rate reached, deferring respawning {} senders of type {}", self.name, missing - respawn_count, type_);
+                }
+                respawn_count
+            },
+        };
+
+        match empty {
+            true => respawn_count.max(1),
+            false => respawn_count,
+        }
+    }
+
+    /// Share of the channel buffers of all senders which is in use, from 0 (idle) to 1 (saturated)
+    fn sender_saturation<T>(&self, senders: &HashMap<usize, Vec<UpsertData<T>>>) -> f64 where T: Upsert<T> + Clone + Send + 'static {
+        let (used, total) = senders.values().flatten().fold((0usize, 0usize), |(used, total), sender| {
+            (used + self.buffer_size - sender.tx.capacity(), total + self.buffer_size)
+        });
+
+        match total {
+            0 => 0f64,
+            _ => used as f64 / total as f64,
+        }
+    }
+
+    /// Scales the introduced lag cycles down linearly with the sender saturation, so a saturated stream flushes right away
+    fn adapt_lag_cycles(&self, saturation: f64) -> usize {
+        let lag_cycles = (self.introduced_lag_cycles as f64 * (1f64 - saturation.clamp(0f64, 1f64))).round() as usize;
+        trace!("{}: sender saturation {:.2}, using {} of {} lag cycles", self.name, saturation, lag_cycles, self.introduced_lag_cycles);
+        lag_cycles
+    }
+
+    /// Whether the lag cycles end early, as nothing was received for a lag cycle while too few records are buffered to wait for a batch
+    fn flushes_below_records(&self, introduced_lag_cycles: usize, buffered: usize) -> bool {
+        match self.flush_below_records {
+            Some(flush_below_records) if introduced_lag_cycles > 1 && buffered < flush_below_records => {
+                trace!("{}: {} buffered records are below {}, skipping the remaining lag cycles", self.name, buffered, flush_below_records);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// The connection creation threshold lowered by the scale up hysteresis while a connection was created within its window
+    fn effective_connection_creation_threshold(&self) -> f64 {
+        match (self.scale_up_hysteresis, *self.last_scale_up.lock().unwrap()) {
+            (Some((threshold_reduction, window)), Some(last_scale_up)) if self.clock().now().saturating_duration_since(last_scale_up) < window => {
+                trace!("{}: connection created {:?} ago, lowering connection creation threshold by {}%", self.name, self.clock().now().saturating_duration_since(last_scale_up), threshold_reduction);
+                (self.connection_creation_threshold() - threshold_reduction).max(0f64)
+            },
+            _ => self.connection_creation_threshold(),
+        }
+    }
+
+    fn notify_rebalance(&self, tier: usize, added: usize, removed: usize, reason: RebalanceReason) {
+        if let Some(rebalance_hook) = &self.rebalance_hook {
+            rebalance_hook(RebalanceEvent { tier, added, removed, reason });
+        }
+    }
+
+    fn notify_dispatch(&self, tier: usize, sender_id: i64, batch_size: usize) {
+        if let Some(dispatch_hook) = &self.dispatch_hook {
+            dispatch_hook(DispatchEvent { tier, sender_id, batch_size });
+        }
+    }
+
+    /// A sender is removable when its capacity is at or above the connection removal threshold, and it is older than the connection removal cooldown
+    fn is_removable<T>(&self, sender: &UpsertData<T>) -> bool where T: Upsert<T> + Clone + Send + 'static {
+        let capacity = sender.tx.capacity() as f64 / self.buffer_size as f64 * 100f64;
+        let idle = match self.connection_removal_threshold {
+            Some(connection_removal_threshold) => capacity >= connection_removal_threshold,
+            None => sender.tx.capacity() == self.buffer_size,
+        };
+        let cooled_down = match self.connection_removal_cooldown {
+            Some(connection_removal_cooldown) => self.clock().now().saturating_duration_since(sender.created_at) >= connection_removal_cooldown,
+            None => true,
+        };
+
+        // a sender still connecting is idle as it gets no batches yet
+        idle && cooled_down && sender.ready.is_none()
+    }
+
+    fn rebalance_senders<T>(&self, senders: &mut HashMap<usize, Vec<UpsertData<T>>>, tx_count: &mut i64) where T: Upsert<T> + Clone + Send + 'static {
+        self.record_sender_topology(senders);
+        trace!("{}: rebalancing database connections", self.name);
+        let mut rebalanced = false;
+        senders.iter_mut().for_each(|(sender_type, sender)| {
+            match self.init_limit(*sender_type) {
+                Some(init_limit) => {
+                    if self.re_balance_sender(sender, init_limit, tx_count, *sender_type) {
+                        rebalanced = true
+                    }
+                },
+                None => {
+                    // the senders are only created for the tiers, see init_senders
+                    debug_assert!(false, "senders of type {} without a tier", sender_type);
+                    error!("{}: senders of type {} have no tier, skipping their rebalancing. check quick_stream::upsert::init_senders<T>(&self, tx_count: &mut i64) function", self.name, sender_type);
+                },
+            }
+        });
+
+        if rebalanced || self.print_con_config {
+            self.print_sender_status(&senders, &tx_count)
+        }
+    }
+
+    /// Level of the repeated status logs, see `QuickStreamBuilder::status_log_level`
+    fn status_log_level(&self) -> Level {
+        self.status_log_level.unwrap_or(Level::Info)
+    }
+
+    fn print_sender_status<T>(&self, senders: &HashMap<usize, Vec<UpsertData<T>>>, tx_count: &i64) where T: Upsert<T> + Clone + Send + 'static {
+        let total_senders_percentage = (*tx_count * 100) as f64 / self.max_con_count() as f64;
+        let sender_amounts = self.tiers().iter().map(|(type_, _)| {
+            format!("            senders {:>5}   :     {}\n", type_, senders.get(type_).map(|senders| senders.len()).unwrap_or(0))
+        }).collect::<String>();
+        log!(self.status_log_level(), " {}: Current Senders (Database Connections) configuration
+                SENDER          AMOUNT
+{}            ____________________________
+            total senders   :     {}
+            total senders % :     {}
+            ============================
+        ", 
+        self.name, 
+        sender_amounts,
+        *tx_count,
+        total_senders_percentage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::{atomic::{AtomicU8, AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use futures::future::BoxFuture;
+    use tokio_postgres::{Client, Error, Statement};
+
+    use tokio::sync::{mpsc, oneshot};
+
+    use crate::{builder, clock::{ManualClock, TokioClock}, error::QuickStreamError, group_by_variant, introduce_lag, remove_duplicates, remove_duplicates_keep_order, split_vec, split_vec_by_given, split_vec_with_fifties};
+
+    use super::{take_saturating, weighted_random_index, BatchConfirmation, ConfirmationSequencer, DispatchEvent, InFlight, PushedBatch, RestartPolicy, StreamState, RebalanceEvent, RebalanceReason, TokenBucket, Tuning, Upsert, RunGuard, UpsertData, UpsertQuickStream, SCALE_UP_READINESS_TIMEOUT};
+
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    struct MockData {
+        id: i64,
+        modified_date: NaiveDateTime,
+    }
+
+    #[async_trait]
+    impl Upsert<MockData> for MockData {
+        fn upsert(
+            _client: &Client,
+            data: Vec<MockData>,
+            _statement: &Statement,
+            _thread_id: i64,
+        ) -> BoxFuture<'static, Result<u64, Error>> {
+            println!("data received, amount : {}", data.len());
+            Box::pin(async { Ok(1) })
+        }
+        
+        fn pkey(&self) -> i64 {
+            self.id
+        }
+
+        fn modified_date(&self) -> NaiveDateTime {
+            self.modified_date
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_duplicates() {
+        let mut data = vec![
+            MockData { id: 1, modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc() },
+            MockData { id: 1, modified_date: DateTime::from_timestamp(1627847281, 0).unwrap().naive_utc() },
+            MockData { id: 2, modified_date: DateTime::from_timestamp(1627847282, 0).unwrap().naive_utc() },
+        ];
+
+        remove_duplicates(&mut data);
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].id, 2);
+        assert_eq!(data[1].id, 1);
+    }
+
+    #[test]
+    fn test_batches_never_repeat_a_pkey() {
+        // 245 records of 120 pkeys, 65 of them are repeated with a later modified date, some of them twice
+        let mut data = (0..120).map(|i| MockData { id: i, modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc() }).collect::<Vec<MockData>>();
+        data.extend((60..120).chain(60..125).map(|i| MockData { id: i % 120, modified_date: DateTime::from_timestamp(1627847290, 0).unwrap().naive_utc() }));
+        assert_eq!(data.len(), 245);
+
+        remove_duplicates(&mut data);
+        let batches = split_vec(data);
+        assert_eq!(batches.iter().map(|batch| batch.len()).sum::<usize>(), 120);
+        for batch in &batches {
+            let mut pkeys = batch.iter().map(|data| data.id).collect::<Vec<i64>>();
+            pkeys.sort();
+            pkeys.dedup();
+            assert_eq!(pkeys.len(), batch.len());
+        }
+        let latest = batches.iter().flatten().filter(|data| data.modified_date == DateTime::from_timestamp(1627847290, 0).unwrap().naive_utc()).count();
+        assert_eq!(latest, 65);
+    }
+
+    #[derive(Clone, Debug)]
+    struct NaturalKeyData {
+        id: i64,
+        natural_key: i64,
+        modified_date: NaiveDateTime,
+    }
+
+    #[async_trait]
+    impl Upsert<NaturalKeyData> for NaturalKeyData {
+        fn upsert(
+            _client: &Client,
+            _data: Vec<NaturalKeyData>,
+            _statement: &Statement,
+            _thread_id: i64,
+        ) -> BoxFuture<'static, Result<u64, Error>> {
+            Box::pin(async { Ok(1) })
+        }
+
+        fn pkey(&self) -> i64 {
+            self.id
+        }
+
+        fn conflict_key(&self) -> i64 {
+            self.natural_key
+        }
+
+        fn modified_date(&self) -> NaiveDateTime {
+            self.modified_date
+        }
+    }
+
+    #[test]
+    fn test_remove_duplicates_by_conflict_key() {
+        let modified_date = DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc();
+        // surrogate keys are unique, natural key 7 is repeated
+        let data = vec![
+            NaturalKeyData { id: 1, natural_key: 7, modified_date },
+            NaturalKeyData { id: 2, natural_key: 8, modified_date },
+            NaturalKeyData { id: 3, natural_key: 7, modified_date: DateTime::from_timestamp(1627847290, 0).unwrap().naive_utc() },
+        ];
+
+        let mut latest = data.clone();
+        remove_duplicates(&mut latest);
+        assert_eq!(latest.iter().map(|data| data.id).collect::<Vec<i64>>(), vec![3, 2]);
+
+        let mut last = data;
+        remove_duplicates_keep_order(&mut last);
+        assert_eq!(last.iter().map(|data| data.id).collect::<Vec<i64>>(), vec![2, 3]);
+    }
+
+    #[derive(Clone, Debug)]
+    struct VariantData {
+        id: i64,
+        deleted: bool,
+        modified_date: NaiveDateTime,
+    }
+
+    #[async_trait]
+    impl Upsert<VariantData> for VariantData {
+        fn upsert(
+            _client: &Client,
+            _data: Vec<VariantData>,
+            _statement: &Statement,
+            _thread_id: i64,
+        ) -> BoxFuture<'static, Result<u64, Error>> {
+            Box::pin(async { Ok(1) })
+        }
+
+        fn pkey(&self) -> i64 {
+            self.id
+        }
+
+        fn modified_date(&self) -> NaiveDateTime {
+            self.modified_date
+        }
+
+        fn statement_variant(&self) -> Option<&str> {
+            self.deleted.then_some("soft_delete")
+        }
+    }
+
+    #[test]
+    fn test_group_by_variant() {
+        let modified_date = DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc();
+        let data = [(1, true), (2, false), (3, true), (4, false), (5, false)].into_iter()
+            .map(|(id, deleted)| VariantData { id, deleted, modified_date })
+            .collect::<Vec<VariantData>>();
+
+        let groups = group_by_variant(data);
+        let ids = groups.iter().map(|group| group.iter().map(|data| data.id).collect::<Vec<i64>>()).collect::<Vec<Vec<i64>>>();
+        assert_eq!(ids, vec![vec![1, 3], vec![2, 4, 5]]);
+        assert_eq!(groups[0][0].statement_variant(), Some("soft_delete"));
+        assert_eq!(groups[1][0].statement_variant(), None);
+    }
+
+    #[test]
+    fn test_remove_duplicates_keep_order() {
+        let modified_date = DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc();
+        let mut data = vec![
+            MockData { id: 3, modified_date },
+            MockData { id: 1, modified_date },
+            MockData { id: 2, modified_date },
+            MockData { id: 1, modified_date: DateTime::from_timestamp(1627847270, 0).unwrap().naive_utc() },
+        ];
+
+        remove_duplicates_keep_order(&mut data);
+        // the last record of id 1 is kept, even though it is older
+        assert_eq!(data.iter().map(|data| data.id).collect::<Vec<i64>>(), vec![3, 2, 1]);
+        assert_eq!(data[2].modified_date, DateTime::from_timestamp(1627847270, 0).unwrap().naive_utc());
+    }
+
+    #[test]
+    fn test_remove_duplicates_never_empties() {
+        let mut data: Vec<MockData> = vec![];
+        remove_duplicates(&mut data);
+        assert!(data.is_empty());
+
+        let mut data = (0..5).map(|i| MockData { id: 1, modified_date: DateTime::from_timestamp(1627847280 + i, 0).unwrap().naive_utc() }).collect::<Vec<_>>();
+        remove_duplicates(&mut data);
+        assert_eq!(data, vec![MockData { id: 1, modified_date: DateTime::from_timestamp(1627847284, 0).unwrap().naive_utc() }]);
+        assert_eq!(split_vec(data).len(), 1);
+    }
+
+    #[test]
+    fn test_split_vec() {
+        let data = (0..110).map(|i| MockData { id: i, modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc() }).collect::<Vec<_>>();
+
+        let result = split_vec(data);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].len(), 100);
+        assert_eq!(result[1].len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_introduce_lag() {
+        let start = std::time::Instant::now();
+        introduce_lag(&TokioClock, 100).await;
+        let duration = start.elapsed();
+        assert!(duration.as_millis() >= 100);
+    }
+
+    #[tokio::test]
+    async fn test_fan_in() {
+        let processor = builder::tests::test_builder().build_update();
+        let (tx_1, rx_1) = mpsc::channel::<Vec<MockData>>(10);
+        let (tx_2, rx_2) = mpsc::channel::<Vec<MockData>>(10);
+        let mut rx = processor.fan_in(vec![rx_1, rx_2]);
+
+        for i in 0..3 {
+            tx_1.send(vec![MockData { id: i, modified_date: Utc::now().naive_utc() }]).await.unwrap();
+            tx_2.send(vec![MockData { id: 100 + i, modified_date: Utc::now().naive_utc() }]).await.unwrap();
+        }
+        drop(tx_1);
+        drop(tx_2);
+
+        let mut received = vec![];
+        while let Some(data) = rx.recv().await {
+            received.extend(data.into_iter().map(|d| d.id));
+        }
+
+        assert_eq!(received.len(), 6);
+        assert_eq!(received.iter().filter(|id| **id < 100).copied().collect::<Vec<i64>>(), vec![0, 1, 2]);
+        assert_eq!(received.iter().filter(|id| **id >= 100).copied().collect::<Vec<i64>>(), vec![100, 101, 102]);
+    }
+
+    #[test]
+    fn test_fan_in_on_runtime_handle() {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let mut builder = builder::tests::test_builder();
+        builder.runtime_handle(runtime.handle().clone());
+        let processor = builder.build_update();
+        let (tx, rx) = mpsc::channel::<Vec<MockData>>(10);
+
+        // called outside of a runtime, where tokio::spawn panics
+        let mut rx = processor.fan_in(vec![rx]);
+        runtime.block_on(async move {
+            tx.send(vec![MockData { id: 1, modified_date: Utc::now().naive_utc() }]).await.unwrap();
+            drop(tx);
+            assert_eq!(rx.recv().await.unwrap().len(), 1);
+            assert!(rx.recv().await.is_none());
+        });
+    }
+
+    #[tokio::test]
+    async fn test_init_sender() {
+        let builder = builder::tests::test_builder();
+        let processor = builder.build_update();
+
+        let count = 10;
+        let mut tx_count = 90;
+        let original_tx_count = tx_count.to_owned();
+        let type_ = 7;
+        let sender = processor.init_sender::<MockData>(count, &mut tx_count, type_);
+
+        assert_eq!(tx_count, 100);
+        assert_eq!(sender.len(), 10);
+        assert_eq!(sender.get(0).unwrap().type_, type_);
+        assert_eq!(sender.get(2).unwrap().id, original_tx_count + 2);
+    }
+
+    #[tokio::test]
+    async fn test_init_senders() {
+        let builder = builder::tests::test_builder();
+        let processor = builder.build_update();
+
+        let mut tx_count = 0;
+        let senders = processor.init_senders::<MockData>(&mut tx_count);
+
+        assert_eq!(senders.len(), 11);
+        assert_eq!(senders.get(&10).unwrap().len(), 12);
+        assert_eq!(senders.get(&100).unwrap().len(), 1);
+
+        assert_eq!(senders.get(&3).unwrap().len(), 2);
+        assert_eq!(senders.get(&5).unwrap().get(0).unwrap().type_, 5);
+        assert_eq!(senders.get(&6).unwrap().get(0).unwrap().id, 10);
+    
+        assert_eq!(tx_count, 31); // 2*9 (single digits) + 12 (tens) + 1 (hundreds) = 31
+    }
+
+    /// A sender whose data ingestor keeps running until the test ends
+    fn running_sender(tx: mpsc::Sender<Vec<MockData>>, id: i64, type_: usize) -> UpsertData<MockData> {
+        UpsertData::new(tx, tokio::spawn(std::future::pending::<u8>()), id, type_, Arc::new(InFlight::default()), Instant::now())
+    }
+
+    fn next_batch(processor: &UpsertQuickStream, rows: usize) -> PushedBatch {
+        processor.next_batch(rows, #[cfg(feature = "tracing")] &tracing::Span::none())
+    }
+
+    fn pushed_batches(in_flight: &InFlight) -> Vec<(u64, usize)> {
+        in_flight.batches.lock().unwrap().iter().map(|batch| (batch.sequence, batch.rows)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_respawn_panicked_sender() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        let mut builder = builder::tests::test_builder();
+        builder.on_rebalance(move |event| events_clone.lock().unwrap().push(event));
+        let processor = builder.build_update();
+
+        let (tx, _rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let panicking = tokio::spawn(async {
+            if true {
+                panic!("panicked in user code")
+            }
+            0u8
+        });
+        let mut senders = vec![UpsertData::new(tx, panicking, 0, 5, Arc::new(InFlight::default()), Instant::now())];
+        while !senders[0].join_handler.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        let mut tx_count = 1;
+
+        assert!(processor.re_balance_sender(&mut senders, 1, &mut tx_count, 5));
+        assert_eq!(senders.len(), 1);
+        assert_eq!(senders[0].id, 0);
+        assert_eq!(tx_count, 1);
+        assert_eq!(*events.lock().unwrap(), vec![
+            RebalanceEvent { tier: 5, added: 0, removed: 1, reason: RebalanceReason::ClosedSenders },
+            RebalanceEvent { tier: 5, added: 1, removed: 0, reason: RebalanceReason::BelowTierMinimum },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_panicked_ingestor_returns_its_permits() {
+        let mut builder = builder::tests::test_builder();
+        builder.max_in_flight_records(20);
+        let processor = builder.build_update();
+        let in_flight_permits = processor.in_flight_permits.clone().unwrap();
+
+        let (tx, _rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let panicking = tokio::spawn(async {
+            if true {
+                panic!("panicked in user code")
+            }
+            0u8
+        });
+        let mut senders = vec![UpsertData::new(tx, panicking, 0, 5, Arc::new(InFlight::default()), Instant::now())];
+        processor.wait_for_in_flight_permits(15).await;
+        processor.acquire_in_flight(&senders[0].in_flight, 15);
+        while !senders[0].join_handler.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        let mut tx_count = 1;
+
+        assert!(processor.re_balance_sender(&mut senders, 1, &mut tx_count, 5));
+        assert_eq!(processor.in_flight_records(), 0);
+        assert_eq!(in_flight_permits.available_permits(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_respawn_closed_senders() {
+        let builder = builder::tests::test_builder();
+        let processor = builder.build_update();
+
+        let mut receivers = vec![];
+        let mut senders = (0..3).map(|id| {
+            let (tx, rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+            receivers.push(rx);
+            running_sender(tx, id, 5)
+        }).collect::<Vec<_>>();
+        let mut tx_count = 3;
+
+        // the data ingestors of two senders stopped receiving
+        receivers.truncate(1);
+
+        assert!(processor.re_balance_sender(&mut senders, 3, &mut tx_count, 5));
+        assert_eq!(senders.len(), 3);
+        assert_eq!(senders.iter().filter(|sender| sender.tx.is_closed()).count(), 0);
+        assert_eq!(tx_count, 3);
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_hook() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        let mut builder = builder::tests::test_builder();
+        builder.on_rebalance(move |event| events_clone.lock().unwrap().push(event));
+        let processor = builder.build_update();
+
+        // idle senders, their receivers are kept alive so none of them is closed
+        let mut receivers = vec![];
+        let mut senders = (0..3).map(|id| {
+            let (tx, rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+            receivers.push(rx);
+            running_sender(tx, id, 5)
+        }).collect::<Vec<_>>();
+        let mut tx_count = 3;
+
+        assert!(processor.re_balance_sender(&mut senders, 1, &mut tx_count, 5));
+        assert_eq!(senders.len(), 1);
+        assert_eq!(*events.lock().unwrap(), vec![RebalanceEvent { tier: 5, added: 0, removed: 2, reason: RebalanceReason::IdleSenders }]);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_hook() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        let mut builder = builder::tests::test_builder();
+        builder.on_dispatch(move |event| events_clone.lock().unwrap().push(event));
+        let processor = builder.build_update();
+
+        // the data ingestor of the first sender stopped, the batch is dispatched to both senders in turn
+        let (closed_tx, _) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let (tx, mut rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let senders = vec![running_sender(closed_tx, 0, 5), running_sender(tx, 1, 5)];
+        let data = (0..5).map(|id| MockData { id, modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc() }).collect::<Vec<MockData>>();
+
+        processor.send_to_available_sender(&senders, data, 5, next_batch(&processor, 5)).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().len(), 5);
+        assert_eq!(*events.lock().unwrap(), vec![
+            DispatchEvent { tier: 5, sender_id: 0, batch_size: 5 },
+            DispatchEvent { tier: 5, sender_id: 1, batch_size: 5 },
+        ]);
+    }
+
+    #[test]
+    fn test_confirmation_sequencer() {
+        let mut confirmation_sequencer = ConfirmationSequencer::default();
+        let sequences = (0..4).map(|_| confirmation_sequencer.next_sequence()).collect::<Vec<u64>>();
+        assert_eq!(sequences, vec![0, 1, 2, 3]);
+
+        // settled out of order, held back until every earlier batch is settled
+        assert!(confirmation_sequencer.confirm(2, 10, true).is_empty());
+        assert!(confirmation_sequencer.confirm(1, 100, false).is_empty());
+        // settled again after being failed, keeps the first outcome
+        assert!(confirmation_sequencer.confirm(1, 100, true).is_empty());
+        assert_eq!(confirmation_sequencer.held_back(), 2);
+        assert_eq!(confirmation_sequencer.confirm(0, 5, true), vec![
+            BatchConfirmation { sequence: 0, rows: 5, written: true },
+            BatchConfirmation { sequence: 1, rows: 100, written: false },
+            BatchConfirmation { sequence: 2, rows: 10, written: true },
+        ]);
+        assert_eq!(confirmation_sequencer.held_back(), 0);
+        // already confirmed, not held back
+        assert!(confirmation_sequencer.confirm(1, 100, true).is_empty());
+        assert_eq!(confirmation_sequencer.held_back(), 0);
+        assert_eq!(confirmation_sequencer.confirm(3, 1, false), vec![BatchConfirmation { sequence: 3, rows: 1, written: false }]);
+    }
+
+    #[test]
+    fn test_flush_barriers() {
+        let mut confirmation_sequencer = ConfirmationSequencer::default();
+
+        // nothing dispatched, released right away
+        let (flushed_tx, mut flushed) = oneshot::channel();
+        confirmation_sequencer.request_flush(Some(flushed_tx));
+        confirmation_sequencer.place_flush_barriers();
+        assert_eq!(flushed.try_recv(), Ok(0));
+
+        let sequences = (0..3).map(|_| confirmation_sequencer.next_sequence()).collect::<Vec<u64>>();
+        let (flushed_tx, mut flushed) = oneshot::channel();
+        confirmation_sequencer.request_flush(Some(flushed_tx));
+        // a flush requested after the barrier waits for the next one
+        let (later_flushed_tx, mut later_flushed) = oneshot::channel();
+        confirmation_sequencer.place_flush_barriers();
+        confirmation_sequencer.request_flush(Some(later_flushed_tx));
+
+        // a batch dispatched after the barrier does not hold the flush back, nor is its failure counted
+        let after_barrier = confirmation_sequencer.next_sequence();
+        confirmation_sequencer.confirm(after_barrier, 5, false);
+        confirmation_sequencer.confirm(sequences[0], 5, true);
+        confirmation_sequencer.confirm(sequences[2], 5, true);
+        assert!(flushed.try_recv().is_err());
+        confirmation_sequencer.confirm(sequences[1], 5, false);
+        assert_eq!(flushed.try_recv(), Ok(1));
+
+        // counts the failure since the previous barrier
+        confirmation_sequencer.place_flush_barriers();
+        assert_eq!(later_flushed.try_recv(), Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_ordered_confirmation_sequences_follow_the_batches() {
+        let confirmations = Arc::new(Mutex::new(Vec::new()));
+        let confirmations_clone = confirmations.clone();
+        let mut builder = builder::tests::test_builder();
+        builder.on_ordered_confirmation(move |confirmation| confirmations_clone.lock().unwrap().push(confirmation));
+        let processor = builder.build_update();
+
+        // the data ingestor of the first sender stopped, the sequence moves with the batch to the next sender
+        let (closed_tx, _) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let (tx, _rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let senders = vec![running_sender(closed_tx, 0, 5), running_sender(tx, 1, 5)];
+        let data = (0..5).map(|id| MockData { id, modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc() }).collect::<Vec<MockData>>();
+
+        for _ in 0..2 {
+            processor.send_to_available_sender(&senders, data.clone(), 5, next_batch(&processor, 5)).await.unwrap();
+        }
+        assert!(pushed_batches(&senders[0].in_flight).is_empty());
+        assert_eq!(pushed_batches(&senders[1].in_flight), vec![(0, 5), (1, 5)]);
+
+        // the second batch is written first
+        processor.confirm_batch(1, 5, true);
+        assert!(confirmations.lock().unwrap().is_empty());
+        processor.confirm_next_batch(&senders[1].in_flight);
+        assert_eq!(*confirmations.lock().unwrap(), vec![
+            BatchConfirmation { sequence: 0, rows: 5, written: true },
+            BatchConfirmation { sequence: 1, rows: 5, written: true },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_batches_release_later_confirmations() {
+        let confirmations = Arc::new(Mutex::new(Vec::new()));
+        let confirmations_clone = confirmations.clone();
+        let mut builder = builder::tests::test_builder();
+        builder.on_ordered_confirmation(move |confirmation| confirmations_clone.lock().unwrap().push(confirmation));
+        let processor = builder.build_update();
+
+        let (tx_0, _rx_0) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let (tx_1, _rx_1) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let senders = vec![running_sender(tx_0, 0, 5), running_sender(tx_1, 1, 5)];
+        let data = (0..5).map(|id| MockData { id, modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc() }).collect::<Vec<MockData>>();
+
+        // the first two batches go to the first sender, the third to the second
+        for sender in [&senders[0], &senders[0], &senders[1]] {
+            processor.send_to_available_sender(std::slice::from_ref(sender), data.clone(), 5, next_batch(&processor, 5)).await.unwrap();
+        }
+
+        // the third batch is written, held back by the batches of the first sender
+        processor.confirm_next_batch(&senders[1].in_flight);
+        assert!(confirmations.lock().unwrap().is_empty());
+
+        // the data ingestor of the first sender stopped without writing its batches
+        processor.fail_batches(&senders[0].in_flight);
+        assert!(pushed_batches(&senders[0].in_flight).is_empty());
+        assert_eq!(*confirmations.lock().unwrap(), vec![
+            BatchConfirmation { sequence: 0, rows: 5, written: false },
+            BatchConfirmation { sequence: 1, rows: 5, written: false },
+            BatchConfirmation { sequence: 2, rows: 5, written: true },
+        ]);
+        assert_eq!(processor.confirmation_sequencer.lock().unwrap().held_back(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_ingestors() {
+        let mut builder = builder::tests::test_builder();
+        builder.stalled_ingestor_threshold(Duration::ZERO);
+        let processor = builder.build_update();
+
+        let (tx, _rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let (idle_tx, _idle_rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let mut senders = vec![running_sender(tx, 0, 5), running_sender(idle_tx, 1, 5)];
+        processor.acquire_in_flight(&senders[0].in_flight, 10);
+        let mut tx_count = 2;
+
+        // counted once per stall, idle senders never stall
+        processor.re_balance_sender(&mut senders, 2, &mut tx_count, 5);
+        processor.re_balance_sender(&mut senders, 2, &mut tx_count, 5);
+        assert_eq!(processor.stalled_ingestor_count(), 1);
+        assert_eq!(senders.len(), 2);
+
+        senders[0].in_flight.progress(Instant::now());
+        processor.re_balance_sender(&mut senders, 2, &mut tx_count, 5);
+        assert_eq!(processor.stalled_ingestor_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_recreate_stalled_ingestors() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        let mut builder = builder::tests::test_builder();
+        builder
+            .stalled_ingestor_threshold(Duration::ZERO)
+            .recreate_stalled_ingestors()
+            .on_rebalance(move |event| events_clone.lock().unwrap().push(event));
+        let processor = builder.build_update();
+
+        let (tx, _rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let (idle_tx, _idle_rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let mut senders = vec![running_sender(tx, 0, 5), running_sender(idle_tx, 1, 5)];
+        processor.acquire_in_flight(&senders[0].in_flight, 10);
+        let mut tx_count = 2;
+
+        assert!(processor.re_balance_sender(&mut senders, 1, &mut tx_count, 5));
+        assert_eq!(senders.len(), 1);
+        assert_eq!(senders[0].id, 1);
+        assert_eq!(tx_count, 1);
+        assert_eq!(processor.in_flight_records(), 0);
+        assert_eq!(*events.lock().unwrap(), vec![RebalanceEvent { tier: 5, added: 0, removed: 1, reason: RebalanceReason::StalledSenders }]);
+    }
+
+    #[tokio::test]
+    async fn test_release_of_recreated_stalled_ingestor() {
+        let mut builder = builder::tests::test_builder();
+        builder.max_in_flight_records(20);
+        let processor = builder.build_update();
+        let in_flight_permits = processor.in_flight_permits.clone().unwrap();
+
+        let in_flight = InFlight::default();
+        processor.wait_for_in_flight_permits(10).await;
+        processor.acquire_in_flight(&in_flight, 10);
+        processor.wait_for_in_flight_permits(5).await;
+        processor.acquire_in_flight(&in_flight, 5);
+        let (other_in_flight, other_records) = (InFlight::default(), 3);
+        processor.wait_for_in_flight_permits(other_records).await;
+        processor.acquire_in_flight(&other_in_flight, other_records);
+
+        // the data ingestor wrote its first batch while being aborted
+        processor.release_in_flight(&in_flight, 10);
+        assert_eq!(processor.release_all_in_flight(&in_flight), 5);
+        // and releases its second batch afterwards, which was released already
+        processor.release_in_flight(&in_flight, 5);
+
+        assert_eq!(in_flight.records.load(Ordering::Acquire), 0);
+        assert_eq!(processor.in_flight_records(), other_records);
+        assert_eq!(in_flight_permits.available_permits(), 20 - other_records);
+    }
+
+    #[test]
+    fn test_take_saturating() {
+        let counter = AtomicUsize::new(5);
+        assert_eq!(take_saturating(&counter, 3), 3);
+        assert_eq!(take_saturating(&counter, 3), 2);
+        assert_eq!(take_saturating(&counter, 3), 0);
+        assert_eq!(counter.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_weighted_random_index() {
+        assert_eq!(weighted_random_index(&[0, 0], 7), None);
+        assert_eq!(weighted_random_index(&[], 7), None);
+
+        // 10 of 40 draws land on the first index, 30 on the last, none on the empty one
+        let picks = (0..40).map(|random| weighted_random_index(&[10, 0, 30], random).unwrap()).collect::<Vec<usize>>();
+        assert_eq!(picks.iter().filter(|index| **index == 0).count(), 10);
+        assert_eq!(picks.iter().filter(|index| **index == 1).count(), 0);
+        assert_eq!(picks.iter().filter(|index| **index == 2).count(), 30);
+    }
+
+    #[tokio::test]
+    async fn test_pick_weighted_random_sender() {
+        let processor = builder::tests::test_builder().build_update();
+        let mut receivers = vec![];
+        let mut senders = (0..3).map(|id| {
+            let (tx, rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+            receivers.push(rx);
+            running_sender(tx, id, 1)
+        }).collect::<Vec<_>>();
+
+        // every sender is idle, so each one is picked by a third of the random numbers
+        let weight = processor.buffer_size as u64;
+        processor.pick_weighted_random_sender(&mut senders, processor.connection_creation_threshold(), weight * 2);
+        assert_eq!(senders.iter().map(|sender| sender.id).collect::<Vec<i64>>(), vec![2, 0, 1]);
+        processor.pick_weighted_random_sender(&mut senders, 100f64, 0);
+        assert_eq!(senders.iter().map(|sender| sender.id).collect::<Vec<i64>>(), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn test_deterministic_random() {
+        let mut builder = builder::tests::test_builder();
+        builder.deterministic();
+        let first = builder.clone().build_update();
+        let second = builder.build_update();
+
+        let sequence = (0..5).map(|_| first.next_random()).collect::<Vec<u64>>();
+        assert_eq!((0..5).map(|_| second.next_random()).collect::<Vec<u64>>(), sequence);
+        // clones share the sequence instead of repeating it
+        assert_ne!(first.clone().next_random(), sequence[0]);
+    }
+
+    #[test]
+    fn test_stream_state() {
+        let processor = builder::tests::test_builder().build_update();
+        assert_eq!(processor.state(), StreamState::Idle);
+
+        for state in [StreamState::Starting, StreamState::Running, StreamState::Draining, StreamState::Stopped] {
+            processor.set_state(state);
+            // shared between clones
+            assert_eq!(processor.clone().state(), state);
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut token_bucket = TokenBucket::new(2, Duration::from_secs(1), Instant::now());
+        let now = token_bucket.refilled_at;
+
+        assert!(token_bucket.try_take(now));
+        assert!(token_bucket.try_take(now));
+        assert!(!token_bucket.try_take(now));
+        // refills one token every 500 millis
+        assert!(!token_bucket.try_take(now + Duration::from_millis(400)));
+        assert!(token_bucket.try_take(now + Duration::from_millis(500)));
+        // never above its capacity
+        assert!(token_bucket.try_take(now + Duration::from_secs(60)));
+        assert!(token_bucket.try_take(now + Duration::from_secs(60)));
+        assert!(!token_bucket.try_take(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_connection_expired() {
+        let clock = ManualClock::new();
+        let mut builder = builder::tests::test_builder();
+        builder.clock(clock.clone());
+        let processor = builder.clone().build_update();
+        let connected_at = processor.clock().now();
+
+        // never expires by default
+        clock.advance(Duration::from_secs(3600));
+        assert!(!processor.connection_expired(connected_at));
+
+        builder.max_connection_lifetime(Duration::from_secs(600));
+        let processor = builder.build_update();
+        let connected_at = processor.clock().now();
+        clock.advance(Duration::from_secs(599));
+        assert!(!processor.connection_expired(connected_at));
+        clock.advance(Duration::from_secs(1));
+        assert!(processor.connection_expired(connected_at));
+    }
+
+    #[test]
+    fn test_pause_scale_up() {
+        let clock = ManualClock::new();
+        let mut builder = builder::tests::test_builder();
+        builder
+            .max_connection_creation_rate(2, Duration::from_secs(10))
+            .too_many_connections_cooldown(Duration::from_secs(30))
+            .clock(clock.clone());
+        let processor = builder.build_update();
+        assert!(!processor.scale_up_paused());
+
+        // the database refused the connection of a new data ingestor
+        processor.pause_scale_up(31, 10);
+        assert!(processor.clone().scale_up_paused());
+        assert!(!processor.take_connection_creation_token());
+
+        clock.advance(Duration::from_secs(29));
+        assert!(processor.scale_up_paused());
+        clock.advance(Duration::from_secs(1));
+        assert!(!processor.scale_up_paused());
+        // refilled evenly during the pause, not beyond its capacity
+        assert!(processor.take_connection_creation_token());
+        assert!(processor.take_connection_creation_token());
+        assert!(!processor.take_connection_creation_token());
+    }
+
+    #[test]
+    fn test_connection_creation_rate() {
+        let mut builder = builder::tests::test_builder();
+        builder.max_connection_creation_rate(1, Duration::from_secs(60));
+        let processor = builder.build_update();
+
+        assert!(processor.take_connection_creation_token());
+        assert!(!processor.clone().take_connection_creation_token());
+
+        let now = Instant::now();
+        processor.record_scale_up(now - Duration::from_secs(30));
+        processor.record_scale_up(now);
+        processor.record_scale_up(now);
+        assert_eq!(processor.scale_ups.lock().unwrap().len(), 2);
+        assert_eq!(processor.connection_creation_rate(), 0.2);
+    }
+
+    #[test]
+    fn test_tune() {
+        let processor = builder::tests::test_builder().build_update();
+
+        // 2 * 9 single digits + 12 tens + 1 hundred
+        assert!(matches!(processor.tune(Tuning::MaxConnectionCount(30)), Err(QuickStreamError::InvalidTuning(_))));
+        assert!(matches!(processor.tune(Tuning::ConnectionCreationThreshold(101.0)), Err(QuickStreamError::InvalidTuning(_))));
+
+        processor.tune(Tuning::MaxConnectionCount(40)).unwrap();
+        processor.clone().tune(Tuning::ConnectionCreationThreshold(30.0)).unwrap();
+        // applied by the main channel receiver only
+        assert_eq!(processor.max_con_count(), 32);
+        assert_eq!(processor.connection_creation_threshold(), 15.0);
+
+        processor.apply_tunings();
+        assert_eq!(processor.max_con_count(), 40);
+        assert_eq!(processor.connection_creation_threshold(), 30.0);
+        assert!(processor.pending_tunings.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_effective_connection_creation_threshold() {
+        let mut builder = builder::tests::test_builder();
+        builder.scale_up_hysteresis(10.0, Duration::from_secs(60));
+        let processor = builder.build_update();
+
+        assert_eq!(processor.effective_connection_creation_threshold(), 15.0);
+
+        *processor.last_scale_up.lock().unwrap() = Some(Instant::now());
+        assert_eq!(processor.effective_connection_creation_threshold(), 5.0);
+
+        *processor.last_scale_up.lock().unwrap() = Instant::now().checked_sub(Duration::from_secs(61));
+        assert_eq!(processor.effective_connection_creation_threshold(), 15.0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_removal_cooldown() {
+        let mut builder = builder::tests::test_builder();
+        builder.connection_removal_cooldown(Duration::from_secs(60));
+        let processor = builder.build_update();
+
+        let mut receivers = vec![];
+        let mut senders = (0..3).map(|id| {
+            let (tx, rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+            receivers.push(rx);
+            running_sender(tx, id, 5)
+        }).collect::<Vec<_>>();
+        let mut tx_count = 3;
+
+        // idle but created just now
+        assert!(!processor.re_balance_sender(&mut senders, 1, &mut tx_count, 5));
+        assert_eq!(senders.len(), 3);
+
+        senders[2].created_at = Instant::now().checked_sub(Duration::from_secs(61)).unwrap();
+        assert!(processor.re_balance_sender(&mut senders, 1, &mut tx_count, 5));
+        assert_eq!(senders.len(), 2);
+        assert!(senders.iter().all(|sender| sender.id != 2));
+        assert_eq!(tx_count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_connection_removal_cooldown_with_manual_clock() {
+        let clock = ManualClock::new();
+        let mut builder = builder::tests::test_builder();
+        builder
+            .connection_removal_cooldown(Duration::from_secs(60))
+            .clock(clock.clone());
+        let processor = builder.build_update();
+
+        let mut receivers = vec![];
+        let mut senders = (0..3).map(|id| {
+            let (tx, rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+            receivers.push(rx);
+            let mut sender = running_sender(tx, id, 5);
+            sender.created_at = processor.clock().now();
+            sender
+        }).collect::<Vec<_>>();
+        let mut tx_count = 3;
+
+        clock.advance(Duration::from_secs(59));
+        assert!(!processor.re_balance_sender(&mut senders, 1, &mut tx_count, 5));
+        assert_eq!(senders.len(), 3);
+
+        // no real time passed, only the clock of the stream
+        clock.advance(Duration::from_secs(2));
+        assert!(processor.re_balance_sender(&mut senders, 1, &mut tx_count, 5));
+        assert_eq!(senders.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_available_sender() {
+        let builder = builder::tests::test_builder();
+        let processor = builder.build_update();
+
+        let (tx_0, rx_0) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let (tx_1, mut rx_1) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let senders = vec![
+            running_sender(tx_0, 0, 1),
+            running_sender(tx_1, 1, 1),
+        ];
+
+        // the data ingestor of the first sender stops after it was chosen
+        drop(rx_0);
+        let data = vec![MockData { id: 1, modified_date: Utc::now().naive_utc() }];
+        processor.send_to_available_sender(&senders, data.clone(), 1, next_batch(&processor, 1)).await.unwrap();
+
+        assert_eq!(rx_1.recv().await.unwrap(), data);
+        assert_eq!(senders[0].in_flight.records.load(Ordering::Acquire), 0);
+        assert_eq!(senders[1].in_flight.records.load(Ordering::Acquire), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_available_sender_all_closed() {
+        let builder = builder::tests::test_builder();
+        let processor = builder.build_update();
+
+        let (tx, rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let senders = vec![running_sender(tx, 0, 1)];
+        drop(rx);
+
+        let result = processor.send_to_available_sender(&senders, vec![MockData { id: 1, modified_date: Utc::now().naive_utc() }], 1, next_batch(&processor, 1)).await;
+        assert!(matches!(result, Err(QuickStreamError::SendersClosed { tier: 1 })));
+    }
+
+    #[tokio::test]
+    async fn test_handle_n_without_senders() {
+        let processor = builder::tests::test_builder().build_update();
+        let mut senders = Vec::<UpsertData<MockData>>::new();
+        let mut tx_count = 0;
+
+        let result = processor.handle_n(vec![MockData { id: 1, modified_date: Utc::now().naive_utc() }], &mut senders, &mut tx_count, 1, next_batch(&processor, 1)).await;
+        assert!(matches!(result, Err(QuickStreamError::SendersClosed { tier: 1 })));
+        assert!(senders.is_empty());
+        assert_eq!(processor.in_flight_records(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_scale_up_sends_through_existing_senders() {
+        let mut builder = builder::tests::test_builder();
+        builder.connection_creation_threshold(100.0);
+        let processor = builder.build_update();
+
+        let (tx, mut rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let mut senders = vec![running_sender(tx, 0, 1)];
+        let mut tx_count = 1;
+
+        // the new sender fails to connect, as the test builder has no host
+        let data = vec![MockData { id: 1, modified_date: Utc::now().naive_utc() }];
+        processor.handle_n(data.clone(), &mut senders, &mut tx_count, 1, next_batch(&processor, 1)).await.unwrap();
+
+        // the batch does not wait for the new sender to connect
+        assert_eq!(rx.recv().await.unwrap(), data);
+        assert_eq!(senders.len(), 2);
+        assert_eq!(tx_count, 2);
+        assert_eq!(pushed_batches(&senders[0].in_flight), vec![(0, 1)]);
+        assert!(pushed_batches(&senders[1].in_flight).is_empty());
+
+        while !senders[1].join_handler.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        processor.poll_connecting_senders(&mut senders, &mut tx_count, 1);
+        assert_eq!(senders.len(), 1);
+        assert_eq!(senders[0].id, 0);
+        assert_eq!(tx_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_poll_connecting_senders() {
+        let clock = ManualClock::new();
+        let mut builder = builder::tests::test_builder();
+        builder.clock(clock.clone());
+        let processor = builder.build_update();
+
+        let mut receivers = vec![];
+        let mut ready_senders = vec![];
+        let mut senders = (0..2).map(|id| {
+            let (tx, rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+            receivers.push(rx);
+            let (ready_tx, ready_rx) = oneshot::channel();
+            ready_senders.push(ready_tx);
+            let mut sender = running_sender(tx, id, 5);
+            sender.created_at = processor.clock().now();
+            sender.ready = Some(ready_rx);
+            sender
+        }).collect::<Vec<_>>();
+        let mut tx_count = 2;
+
+        ready_senders.remove(0).send(Ok(())).unwrap();
+        processor.poll_connecting_senders(&mut senders, &mut tx_count, 5);
+        assert_eq!(senders.len(), 2);
+        assert!(senders[0].ready.is_none());
+        assert!(senders[1].ready.is_some());
+
+        // the second one never answers
+        clock.advance(SCALE_UP_READINESS_TIMEOUT);
+        processor.poll_connecting_senders(&mut senders, &mut tx_count, 5);
+        assert_eq!(senders.len(), 1);
+        assert_eq!(senders[0].id, 0);
+        assert_eq!(tx_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_respawn_within_scale_up_limits() {
+        let mut builder = builder::tests::test_builder();
+        builder.max_connection_count(3);
+        let processor = builder.build_update();
+
+        let mut receivers = vec![];
+        let mut senders = (0..3).map(|id| {
+            let (tx, rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+            receivers.push(rx);
+            running_sender(tx, id, 5)
+        }).collect::<Vec<_>>();
+        // senders of other tiers use up the max connection count
+        let mut tx_count = 5;
 
-        info!("{}:{}:{}: creating database client", self.name, n, thread_id);
-        let client = self.get_db_client().await;
-        info!("{}:{}:{}: creating database client success", self.name, n, thread_id);
+        receivers.truncate(1);
+        assert!(processor.re_balance_sender(&mut senders, 3, &mut tx_count, 5));
+        assert_eq!(senders.len(), 1);
+        assert_eq!(tx_count, 3);
 
-        info!("{}:{}:{}: preparing query and creating statement", self.name, n, thread_id);
-        let statement = client.prepare(query.as_str()).await.unwrap();
-        info!("{}:{}:{}: query prepared and created statement successfully", self.name, n, thread_id);
+        // deferred while scaling up is paused, even within the max connection count
+        processor.pause_scale_up(0, 5);
+        tx_count = 1;
+        assert!(!processor.re_balance_sender(&mut senders, 3, &mut tx_count, 5));
+        assert_eq!(senders.len(), 1);
 
-        info!("{}:{}:{}: data ingestor channel receiver starting", self.name, n, thread_id);
-        while let Some(data) = rx.recv().await {
-            trace!("{}:{}:{}: data received pushing for ingestion. pkeys: {:?}", self.name, n, thread_id, data.iter().map(|f| f.pkey()).collect::<Vec<i64>>());
-            T::upsert(&client, data, &statement, thread_id).await?;
-        }
+        // a tier left without senders gets one regardless
+        receivers.clear();
+        assert!(processor.re_balance_sender(&mut senders, 3, &mut tx_count, 5));
+        assert_eq!(senders.len(), 1);
+        assert_eq!(tx_count, 1);
+    }
 
-        info!("{}:{}:{} shutting down data ingestor", self.name, n, thread_id);
-        Ok(())
+    #[test]
+    fn test_run_guard() {
+        let state = AtomicU8::new(StreamState::Running.as_u8());
+        let run_guard = RunGuard { name: "test", state: &state, finished: false };
+        drop(run_guard);
+        assert_eq!(StreamState::from_u8(state.load(Ordering::Acquire)), StreamState::Stopped);
+
+        // run stops a stream failing with an error itself, it was not dropped
+        let state = AtomicU8::new(StreamState::Running.as_u8());
+        let mut run_guard = RunGuard { name: "test", state: &state, finished: false };
+        let error = run_guard.fail(QuickStreamError::SendersClosed { tier: 1 });
+        drop(run_guard);
+        assert!(matches!(error, QuickStreamError::SendersClosed { tier: 1 }));
+        assert_eq!(StreamState::from_u8(state.load(Ordering::Acquire)), StreamState::Running);
     }
 
-    /**
-     * n is redunt here as n is the same as type_ ***need to remove n***
-     */
-    fn init_sender<T>(&self, n: usize, count: usize, tx_count: &mut i64, type_: usize) -> Vec<UpsertData<T>> where T: Upsert<T> + Clone + Send + 'static {
-        trace!("{}: initiating sender, creating {} upsert senders", self.name, count);
-        let mut senders = vec![];
-    
-        for _ in 0..count {
-            let (tx_t, rx_t) = mpsc::channel::<Vec<T>>(self.buffer_size);
-    
-            let thread_id = tx_count.clone();
-            let query = self.queries.get(&n);
-            let n_clone = n.clone();
-            let self_clone = self.to_owned();
-            let handler = tokio::spawn(async move {
-                let _ = self_clone.process_n(query, rx_t, thread_id, n_clone).await;
-                1u8
-            });
-    
-            let tx_struct = UpsertData::new(tx_t, handler, tx_count.clone(), type_);
-    
-            *tx_count += 1;
-    
-            senders.push(tx_struct);
-        }
-    
-        senders
+    #[tokio::test]
+    async fn test_push_to_handle_without_tier() {
+        let processor = builder::tests::test_builder().build_update();
+        let mut senders = HashMap::<usize, Vec<UpsertData<MockData>>>::new();
+        let mut tx_count = 0;
+
+        let data = (0..3).map(|id| MockData { id, modified_date: Utc::now().naive_utc() }).collect::<Vec<MockData>>();
+        let result = processor.push_to_handle(&mut senders, vec![data], &mut tx_count).await;
+        assert!(matches!(result, Err(QuickStreamError::MissingTier { tier: 3 })));
     }
 
-    fn init_senders<T>(&self, tx_count: &mut i64) -> HashMap<usize, Vec<UpsertData<T>>> where T: Upsert<T> + Clone + Send + 'static {
-        trace!("{}: creating sender map of capacity 11", self.name);
-        let mut sender_map = HashMap::with_capacity(11);
-        
-        trace!("{}: creating data senders from 1-10 and 100", self.name);
-        let senders_1 = self.init_sender::<T>(1, self.single_digits, tx_count, 1);
-        let senders_2 = self.init_sender::<T>(2, self.single_digits, tx_count, 2);
-        let senders_3 = self.init_sender::<T>(3, self.single_digits, tx_count, 3);
-        let senders_4 = self.init_sender::<T>(4, self.single_digits, tx_count, 4);
-        let senders_5 = self.init_sender::<T>(5, self.single_digits, tx_count, 5);
-        let senders_6 = self.init_sender::<T>(6, self.single_digits, tx_count, 6);
-        let senders_7 = self.init_sender::<T>(7, self.single_digits, tx_count, 7);
-        let senders_8 = self.init_sender::<T>(8, self.single_digits, tx_count, 8);
-        let senders_9 = self.init_sender::<T>(9, self.single_digits, tx_count, 9);
-        let senders_10 = self.init_sender::<T>(10, self.tens, tx_count, 10);
-        trace!("{}: creating data senders from 1-10 success", self.name);
-
-        let senders_100 = self.init_sender::<T>(1, self.hundreds, tx_count, 100);
-        trace!("{}: creating data senders for 100 success", self.name);
-
-        sender_map.insert(1, senders_1);
-        sender_map.insert(2, senders_2);
-        sender_map.insert(3, senders_3);
-        sender_map.insert(4, senders_4);
-        sender_map.insert(5, senders_5);
-        sender_map.insert(6, senders_6);
-        sender_map.insert(7, senders_7);
-        sender_map.insert(8, senders_8);
-        sender_map.insert(9, senders_9);
-        sender_map.insert(10, senders_10);
-
-        sender_map.insert(100, senders_100);
+    #[test]
+    fn test_adapt_lag_cycles() {
+        let mut builder = builder::tests::test_builder();
+        builder.introduced_lag_cycles(10).adaptive_lag_cycles();
+        let processor = builder.build_update();
 
-        self.print_sender_status(&sender_map, &tx_count);
+        assert_eq!(processor.adapt_lag_cycles(0.0), 10);
+        assert_eq!(processor.adapt_lag_cycles(0.25), 8);
+        assert_eq!(processor.adapt_lag_cycles(1.0), 0);
+        assert_eq!(processor.adapt_lag_cycles(1.5), 0);
+    }
 
-        sender_map
+    #[test]
+    fn test_flushes_below_records() {
+        let mut builder = builder::tests::test_builder();
+        builder.flush_below_records(5);
+        let processor = builder.build_update();
+
+        // the first empty poll only starts the first lag cycle
+        assert!(!processor.flushes_below_records(1, 1));
+        assert!(processor.flushes_below_records(2, 4));
+        assert!(!processor.flushes_below_records(2, 5));
+        assert!(!builder::tests::test_builder().build_update().flushes_below_records(2, 1));
     }
 
-    async fn push_to_handle<T>(&self, senders: &mut HashMap<usize, Vec<UpsertData<T>>>, vec_data: Vec<Vec<T>>, tx_count: &mut i64) where T: Upsert<T> + Clone + Send + 'static {
-        for data in vec_data {
-            let k = data.len();
-            self.handle_n(data,
-                 senders.get_mut(&k)
-                    .expect("Unreachable logic reached. Check quick_stream::split_vec<T>(data: Vec<T>) function"), 
-                 tx_count, k).await;
+    #[tokio::test]
+    async fn test_sender_saturation() {
+        let builder = builder::tests::test_builder();
+        let processor = builder.build_update();
+
+        let (tx_0, _rx_0) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let (tx_1, _rx_1) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        for _ in 0..5 {
+            tx_0.send(vec![]).await.unwrap();
         }
+        let mut senders = HashMap::new();
+        senders.insert(1, vec![running_sender(tx_0, 0, 1)]);
+        senders.insert(10, vec![running_sender(tx_1, 1, 10)]);
+
+        assert_eq!(processor.sender_saturation(&senders), 0.25);
+        assert_eq!(processor.sender_saturation(&HashMap::<usize, Vec<UpsertData<MockData>>>::new()), 0.0);
     }
 
-    async fn handle_n<T>(&self, data: Vec<T>, senders: &mut Vec<UpsertData<T>>, tx_count: &mut i64, type_: usize) where T: Upsert<T> + Clone + Send + 'static {
-        trace!("{}: handeling data started", self.name);
-        trace!("{}: sorting senders by capacity to get the channel with highest capacity", self.name);
-        senders.sort_by(|x, y| y.tx.capacity().cmp(&x.tx.capacity()));
+    #[tokio::test]
+    async fn test_sender_topology() {
+        let clock = ManualClock::new();
+        let mut builder = builder::tests::test_builder();
+        builder.clock(clock.clone());
+        let processor = builder.build_update();
+        assert_eq!(processor.sender_topology(), None);
 
-        let sender_0 = match senders.first() {
-            Some(sender) => sender,
-            None => {
-                error!("{}: no senders found, this is an impossible scenario", self.name);
-                panic!("no senders found, impossible scenario")
-            },
-        };
+        let (tx_0, _rx_0) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let (tx_1, _rx_1) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        tx_0.send(vec![]).await.unwrap();
+        let mut busy = running_sender(tx_0, 0, 1);
+        busy.created_at = processor.clock().now();
+        processor.acquire_in_flight(&busy.in_flight, 3);
+        let mut idle = running_sender(tx_1, 1, 10);
+        idle.created_at = processor.clock().now();
+        *idle.in_flight.last_error.lock().unwrap() = Some("database error : connection reset".to_string());
+        let mut senders = HashMap::new();
+        senders.insert(1, vec![busy]);
+        senders.insert(10, vec![idle]);
 
-        let capacity = sender_0.tx.capacity() as f64 / self.buffer_size as f64 * 100f64;
+        clock.advance(Duration::from_millis(1500));
+        processor.record_sender_topology(&senders);
+        let sender_topology = processor.clone().sender_topology().unwrap();
 
-        if capacity <= self.connection_creation_threshold {
-            warn!("{}: capacity of {}:{} {}% is below connection creation threshold {}%", self.name, sender_0.type_, sender_0.id, capacity, self.connection_creation_threshold);
+        assert_eq!(sender_topology.total_senders, 2);
+        assert_eq!(sender_topology.max_connection_count, processor.max_con_count());
+        assert_eq!(sender_topology.tiers.len(), processor.tiers().len());
+        let tier_1 = &sender_topology.tiers.iter().find(|tier| tier.tier == 1).unwrap().senders[0];
+        assert_eq!((tier_1.id, tier_1.capacity, tier_1.in_flight_records, tier_1.idle_millis), (0, 90.0, 3, None));
+        let tier_10 = &sender_topology.tiers.iter().find(|tier| tier.tier == 10).unwrap().senders[0];
+        assert_eq!((tier_10.capacity, tier_10.idle_millis), (100.0, Some(1500)));
+        assert_eq!(tier_10.last_error.as_deref(), Some("database error : connection reset"));
+        assert!(sender_topology.tiers.iter().find(|tier| tier.tier == 2).unwrap().senders.is_empty());
+    }
 
-            if *tx_count < self.max_con_count as i64 {
-                info!("{}: creating a sender of type {} since current connections {} is below allowed max connections count {}", self.name, type_, *tx_count, self.max_con_count);
-                let (tx_t, rx_t) = mpsc::channel::<Vec<T>>(self.buffer_size);
+    #[test]
+    fn test_durable_watermark() {
+        let mut builder = builder::tests::test_builder();
+        builder.watermark_query("UPDATE watermarks SET watermark = $2 WHERE name = $1".to_string(), Duration::from_secs(1));
+        let processor = builder.build_update();
+        let earlier = DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc();
+        let later = DateTime::from_timestamp(1627847290, 0).unwrap().naive_utc();
 
-                let thread_id = tx_count.clone();
-                let n = data.len();
-                let query = self.queries.get(&n);
-                let self_clone = Arc::new(self.to_owned());
-                let handler = tokio::spawn(async move {
-                    let _ = self_clone.process_n(query, rx_t, thread_id, n).await;
-                    0u8
-                });
+        processor.observe_watermark(Some(later), true);
+        processor.observe_watermark(Some(earlier), true);
+        assert_eq!(processor.durable_watermark(), Some(later));
 
-                match tx_t.send(data).await {
-                    Ok(_) => {
-                        let tx_struct = UpsertData::new(tx_t, handler, tx_count.clone(), type_);
-                        info!("{}: creating sender {}:{} successful", self.name, tx_struct.type_, tx_struct.id);
-                        *tx_count += 1;
-                        senders.push(tx_struct);
-
-                        if *tx_count == self.max_con_count as i64 {
-                            eprintln!("warn max connection count reached")
-                        } else {
-                            println!("connection created, current total connections : {}", tx_count)
-                        }
-                    },
-                    Err(error) => {
-                        error!("{}: creating sender failed with error: {}", self.name, error);
-                        panic!("{}: failed to send data through the newly created channel {}", self.name, error)
-                    },
-                };
-            } else {
-                error!("{}: unable to create connection as max connection count has already reached", self.name);
-                warn!("{}: PROCESSOR WILL HAVE TO WAIT UNTIL CAPACITY IS AVAIALABLE TO PROCEED", self.name);
-                match sender_0.tx.send(data).await {
-                    Ok(_) => info!("{}: data successfully pushed after capacity was available", self.name),
-                    Err(error) => {
-                        panic!("{}: failed to send data through the channel of sender {}:{} : {}", self.name, sender_0.type_, sender_0.id, error)
-                    },
-                }
-            }
-        } else {
-            info!("{}: capacity of sender {}:{} is at {}%", self.name, sender_0.type_, sender_0.id, capacity);
-            match sender_0.tx.send(data).await {
-                Ok(_) => {
-                    trace!("{}: pushing to data ingestor success using sender {}:{}", self.name, sender_0.type_, sender_0.id);
-                },
-                Err(error) => {
-                    panic!("{}: failed to send data through the channel of sender {}:{} : {}", self.name, sender_0.type_, sender_0.id, error)
-                },
-            };
-        }
+        let in_flight = InFlight::default();
+        processor.acquire_in_flight(&in_flight, 1);
+        assert_eq!(processor.durable_watermark(), None);
+        processor.release_in_flight(&in_flight, 1);
+
+        processor.observe_watermark(Some(later), false);
+        assert_eq!(processor.durable_watermark(), None);
+        assert_eq!(processor.written_watermark(), Some(later));
     }
 
-    fn re_balance_sender<T>(&self, senders: &mut Vec<UpsertData<T>>, init_limit: usize, tx_count: &mut i64, type_: usize) -> bool where T: Upsert<T> + Clone + Send + 'static {
+    #[test]
+    fn test_verify_returning() {
+        let (tx, _rx) = mpsc::channel(1);
+        let mut builder = builder::tests::test_builder();
+        builder.returning(tx);
+        let processor = builder.build_update();
 
-        trace!("{}: rebalancing senders of type {}", self.name, type_);
+        // MockData keeps the default upsert_returning
+        assert!(matches!(processor.verify_returning::<MockData>(), Err(QuickStreamError::ReturningUnsupported)));
+        assert!(builder::tests::test_builder().build_update().verify_returning::<MockData>().is_ok());
+    }
 
-        let start_senders = senders.len();
-        senders.retain(|upsert_data| !upsert_data.tx.is_closed() || upsert_data.join_handler.is_finished());
+    #[test]
+    fn test_chunk_payload() {
+        let processor = builder::tests::test_builder().build_update();
 
-        let removed_senders = start_senders - senders.len();
+        let mut data = (0..250).map(|i| MockData { id: i, modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc() }).collect::<Vec<_>>();
+        data.push(MockData { id: 0, modified_date: DateTime::from_timestamp(1627847290, 0).unwrap().naive_utc() });
 
-        if removed_senders > 0 {
-            info!("{}: removed {} senders of type {}", self.name, removed_senders, type_);
-            *tx_count -= removed_senders as i64;
-        }
+        let (chunks, remainder) = processor.chunk_payload(data, 100);
+        assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![100, 100]);
+        assert_eq!(remainder.len(), 50);
+        assert_eq!(chunks.iter().flatten().chain(remainder.iter()).filter(|record| record.id == 0).count(), 1);
+    }
 
-        if senders.len() > init_limit {
-            let full_capacity_count = senders.iter().filter(|sender| sender.tx.capacity() == self.buffer_size).collect::<Vec<&UpsertData<T>>>().len();
-    
-            if full_capacity_count > 0 {
-                let mut amount_to_pop = full_capacity_count - (full_capacity_count / 2usize);
-                if senders.len() - amount_to_pop < init_limit {
-                    amount_to_pop = senders.len() - init_limit;
-                }
-                senders.sort_by(|x, y| x.tx.capacity().cmp(&y.tx.capacity()));
-                for _ in 0..amount_to_pop {
-                    senders.pop();
-                    *tx_count -= 1;
-                }
-            }
-        }
+    #[test]
+    fn test_in_flight_records() {
+        let builder = builder::tests::test_builder();
+        let processor = builder.build_update();
 
-        trace!("{}: rebalancing senders of type {} complete", self.name, type_);
-        senders.len() != start_senders
+        let in_flight_1 = InFlight::default();
+        let in_flight_2 = InFlight::default();
+        processor.acquire_in_flight(&in_flight_1, 10);
+        processor.acquire_in_flight(&in_flight_2, 100);
+        assert_eq!(processor.in_flight_records(), 110);
+
+        processor.release_in_flight(&in_flight_2, 100);
+        assert_eq!(in_flight_1.records.load(Ordering::Acquire), 10);
+        assert_eq!(in_flight_2.records.load(Ordering::Acquire), 0);
+        assert_eq!(processor.in_flight_records(), 10);
     }
 
-    fn rebalance_senders<T>(&self, senders: &mut HashMap<usize, Vec<UpsertData<T>>>, tx_count: &mut i64) where T: Upsert<T> + Clone + Send + 'static {
-        trace!("{}: rebalancing database connections", self.name);
-        let mut rebalanced = false;
-        senders.iter_mut().for_each(|(sender_type, sender)| {
-            if *sender_type < 10 {
-                if self.re_balance_sender(sender, self.single_digits, tx_count, *sender_type) {
-                    rebalanced = true
-                }
-            } else if *sender_type == 10 {
-                if self.re_balance_sender(sender, self.tens, tx_count, *sender_type) {
-                    rebalanced = true
-                }
-            } else if *sender_type == 100 {
-                if self.re_balance_sender(sender, self.hundreds, tx_count, *sender_type) {
-                    rebalanced = true
-                }
-            } else {
-                error!("{}: Impossible Scenario, Check quick_stream::upsert::init_senders<T>(&self, tx_count: &mut i64) function", self.name);
-                panic!("Unreachable logic reached. Check quick_stream::upsert::init_senders<T>(&self, tx_count: &mut i64) function")
-            }
-        });
+    #[test]
+    fn test_take_quota() {
+        let mut builder = builder::tests::test_builder();
+        builder.max_total_rows(150);
+        let processor = builder.build_update();
 
-        if rebalanced || self.print_con_config {
-            self.print_sender_status(&senders, &tx_count)
-        }
+        let mut data = (0..100).collect::<Vec<i64>>();
+        assert!(!processor.take_quota(&mut data));
+        assert_eq!(data.len(), 100);
+
+        let mut data = (0..100).collect::<Vec<i64>>();
+        assert!(processor.take_quota(&mut data));
+        assert_eq!(data, (0..50).collect::<Vec<i64>>());
+        assert_eq!(processor.total_rows(), 150);
+
+        let mut data = (0..10).collect::<Vec<i64>>();
+        assert!(processor.take_quota(&mut data));
+        assert!(data.is_empty());
+        assert_eq!(processor.total_rows(), 150);
     }
 
-    fn print_sender_status<T>(&self, senders: &HashMap<usize, Vec<UpsertData<T>>>, tx_count: &i64) where T: Upsert<T> + Clone + Send + 'static {
-        let total_senders_percentage = (*tx_count * 100) as f64 / self.max_con_count as f64;
-        info!(" {}: Current Senders (Database Connections) configuration
-                SENDER          AMOUNT
-            senders     1   :     {}
-            senders     2   :     {}
-            senders     3   :     {}
-            senders     4   :     {}
-            senders     5   :     {}
-            senders     6   :     {}
-            senders     7   :     {}
-            senders     8   :     {}
-            senders     9   :     {}
-            senders    10   :     {}
-            senders   100   :     {}
-            ____________________________
-            total senders   :     {}
-            total senders % :     {}
-            ============================
-        ", 
-        self.name, 
-        senders.get(&1).unwrap().len(), 
-        senders.get(&2).unwrap().len(), 
-        senders.get(&3).unwrap().len(), 
-        senders.get(&4).unwrap().len(), 
-        senders.get(&5).unwrap().len(), 
-        senders.get(&6).unwrap().len(), 
-        senders.get(&7).unwrap().len(), 
-        senders.get(&8).unwrap().len(), 
-        senders.get(&9).unwrap().len(), 
-        senders.get(&10).unwrap().len(), 
-        senders.get(&100).unwrap().len(),
-        *tx_count,
-        total_senders_percentage)
+    #[tokio::test]
+    async fn test_wait_for_in_flight_budget() {
+        let mut builder = builder::tests::test_builder();
+        builder.max_in_flight_records_per_connection(100);
+        let processor = builder.build_update();
+
+        let in_flight = Arc::new(InFlight::default());
+        // nothing in flight, a batch larger than the budget still passes
+        processor.wait_for_in_flight_budget(&in_flight, 150).await;
+
+        processor.acquire_in_flight(&in_flight, 100);
+        let waiting_processor = processor.clone();
+        let waiting_in_flight = in_flight.clone();
+        let waiting = tokio::spawn(async move {
+            waiting_processor.wait_for_in_flight_budget(&waiting_in_flight, 10).await;
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        processor.release_in_flight(&in_flight, 100);
+        waiting.await.unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use async_trait::async_trait;
-    use chrono::{DateTime, NaiveDateTime, Utc};
-    use futures::future::BoxFuture;
-    use tokio_postgres::{Client, Error, Statement};
+    #[tokio::test]
+    async fn test_wait_for_in_flight_permits() {
+        let mut builder = builder::tests::test_builder();
+        builder.max_in_flight_records(100);
+        let processor = builder.build_update();
 
-    use crate::{builder, introduce_lag, remove_duplicates, split_vec, split_vec_by_given};
+        // two connections share the cap
+        let in_flight_1 = Arc::new(InFlight::default());
+        let in_flight_2 = Arc::new(InFlight::default());
+        processor.wait_for_in_flight_permits(60).await;
+        processor.acquire_in_flight(&in_flight_1, 60);
+        processor.wait_for_in_flight_permits(40).await;
+        processor.acquire_in_flight(&in_flight_2, 40);
 
-    use super::Upsert;
+        let waiting_processor = processor.clone();
+        let waiting = tokio::spawn(async move {
+            // larger than the cap, it waits until nothing is in flight
+            waiting_processor.wait_for_in_flight_permits(150).await;
+        });
 
-    #[derive(Clone, PartialEq, Eq, Debug)]
-    struct MockData {
-        id: i64,
-        modified_date: NaiveDateTime,
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        processor.release_in_flight(&in_flight_1, 60);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        processor.release_in_flight(&in_flight_2, 40);
+        waiting.await.unwrap();
+        assert_eq!(processor.in_flight_permits.as_ref().unwrap().available_permits(), 0);
     }
 
-    #[async_trait]
-    impl Upsert<MockData> for MockData {
-        fn upsert(
-            _client: &Client,
-            data: Vec<MockData>,
-            _statement: &Statement,
-            _thread_id: i64,
-        ) -> BoxFuture<'static, Result<u64, Error>> {
-            println!("data received, amount : {}", data.len());
-            Box::pin(async { Ok(1) })
-        }
-        
-        fn pkey(&self) -> i64 {
-            self.id
-        }
+    #[tokio::test]
+    async fn test_discard_queued() {
+        let processor = builder::tests::test_builder().build_update();
+        let in_flight = InFlight::default();
+        let (tx, mut rx) = mpsc::channel::<Vec<MockData>>(10);
+        let modified_date = DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc();
 
-        fn modified_date(&self) -> NaiveDateTime {
-            self.modified_date
+        for id in 0..3 {
+            processor.acquire_in_flight(&in_flight, 2);
+            tx.send(vec![MockData { id, modified_date }, MockData { id: id + 10, modified_date }]).await.unwrap();
         }
+        processor.discard_queued(&mut rx, &in_flight, 2, 0);
+
+        assert_eq!(processor.in_flight_records(), 0);
+        assert!(tx.send(vec![MockData { id: 1, modified_date }]).await.is_err());
     }
 
     #[tokio::test]
-    async fn test_remove_duplicates() {
-        let mut data = vec![
-            MockData { id: 1, modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc() },
-            MockData { id: 1, modified_date: DateTime::from_timestamp(1627847281, 0).unwrap().naive_utc() },
-            MockData { id: 2, modified_date: DateTime::from_timestamp(1627847282, 0).unwrap().naive_utc() },
-        ];
+    async fn test_failed_readiness_stops_data_ingestor() {
+        let processor = builder::tests::test_builder().build_update();
+        let in_flight = Arc::new(InFlight::default());
+        let (tx, rx) = mpsc::channel::<Vec<MockData>>(processor.buffer_size);
+        let (ready_tx, ready_rx) = oneshot::channel();
 
-        remove_duplicates(&mut data);
-        assert_eq!(data.len(), 2);
-        assert_eq!(data[0].id, 2);
-        assert_eq!(data[1].id, 1);
+        // the db config has no host, connecting fails right away
+        let result = processor.process_n(rx, 0, 1, in_flight.clone(), Some(ready_tx)).await;
+        assert!(matches!(result, Err(QuickStreamError::IngestorStopped { tier: 1, id: 0 })));
+        let error = ready_rx.await.unwrap().unwrap_err();
+        assert_eq!(in_flight.last_error.lock().unwrap().to_owned(), Some(error.to_string()));
+        assert!(tx.is_closed());
     }
 
-    #[test]
-    fn test_split_vec() {
-        let data = (0..110).map(|i| MockData { id: i, modified_date: DateTime::from_timestamp(1627847280, 0).unwrap().naive_utc() }).collect::<Vec<_>>();
+    #[tokio::test]
+    async fn test_with_connect_timeout() {
+        let mut builder = builder::tests::test_builder();
+        builder.connect_timeout(Duration::from_millis(50));
+        let processor = builder.build_update();
 
-        let result = split_vec(data);
-        assert_eq!(result.len(), 2);
-        assert_eq!(result[0].len(), 100);
-        assert_eq!(result[1].len(), 10);
+        assert_eq!(processor.with_connect_timeout(async { Ok::<i32, Error>(1) }).await.unwrap(), 1);
     }
 
     #[tokio::test]
-    async fn test_introduce_lag() {
-        let start = std::time::Instant::now();
-        introduce_lag(100).await;
-        let duration = start.elapsed();
-        assert!(duration.as_millis() >= 100);
+    async fn test_with_connect_timeout_expired() {
+        let mut builder = builder::tests::test_builder();
+        builder.connect_timeout(Duration::from_millis(50));
+        let processor = builder.build_update();
+
+        let result = processor.with_connect_timeout(std::future::pending::<Result<(), Error>>()).await;
+        assert!(matches!(result, Err(QuickStreamError::ConnectTimeout(timeout)) if timeout == Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_restart_policy_backoff() {
+        let restart_policy = RestartPolicy { max_restarts: 10, backoff: Duration::from_millis(100), max_backoff: Duration::from_secs(1) };
+        assert_eq!(restart_policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(restart_policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(restart_policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(restart_policy.backoff(4), Duration::from_secs(1));
+        assert_eq!(restart_policy.backoff(usize::MAX), Duration::from_secs(1));
     }
 
     #[tokio::test]
-    async fn test_init_sender() {
-        let builder = builder::tests::test_builder();
+    async fn test_run_supervised_gives_up() {
+        let mut builder = builder::tests::test_builder();
+        // the test config has no host, so verifying the schema fails on every run
+        builder
+            .verify_schema()
+            .expected_columns("test".to_string(), vec![("id".to_string(), "bigint".to_string())]);
         let processor = builder.build_update();
 
-        let n = 7;
-        let count = 10;
-        let mut tx_count = 90;
-        let original_tx_count = tx_count.to_owned();
-        let type_ = 7;
-        let sender = processor.init_sender::<MockData>(n, count, &mut tx_count, type_);
+        let (_tx, rx) = mpsc::channel::<Vec<MockData>>(10);
+        let restart_policy = RestartPolicy { max_restarts: 2, backoff: Duration::from_millis(20), max_backoff: Duration::from_millis(20) };
+        let started = Instant::now();
+        let result = processor.run_supervised(rx, restart_policy).await;
 
-        assert_eq!(tx_count, 100);
-        assert_eq!(sender.len(), 10);
-        assert_eq!(sender.get(0).unwrap().type_, type_);
-        assert_eq!(sender.get(2).unwrap().id, original_tx_count + 2);
+        assert!(matches!(result, Err(QuickStreamError::Database(_))));
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(processor.state(), StreamState::Stopped);
     }
 
-    #[tokio::test]
-    async fn test_init_senders() {
+    #[test]
+    fn test_tiers() {
         let builder = builder::tests::test_builder();
         let processor = builder.build_update();
 
-        let mut tx_count = 0;
-        let senders = processor.init_senders::<MockData>(&mut tx_count);
+        let tiers = processor.tiers();
+        assert_eq!(tiers.len(), 11);
+        assert_eq!(tiers.iter().map(|(type_, _)| *type_).collect::<Vec<usize>>(), vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 100]);
+        assert_eq!(tiers[0], (1, 2));
+        assert_eq!(tiers[9], (10, 12));
+        assert_eq!(tiers[10], (100, 1));
+        assert_eq!(processor.init_limit(100), Some(1));
+        assert_eq!(processor.init_limit(11), None);
+        assert_eq!(processor.init_limit(50), None);
+    }
 
-        assert_eq!(senders.len(), 11);
-        assert_eq!(senders.get(&10).unwrap().len(), 12);
-        assert_eq!(senders.get(&100).unwrap().len(), 1);
+    #[test]
+    fn test_split_vec_with_fifties() {
+        let data: Vec<MockData> = (0..290).map(|i| MockData { id: i, modified_date: Utc::now().naive_utc() }).collect();
+        let result = split_vec_with_fifties(data);
+        assert_eq!(result.iter().map(|batch| batch.len()).collect::<Vec<usize>>(), vec![100, 100, 50, 10, 10, 10, 10]);
+        assert_eq!(result.iter().flatten().map(|data| data.id).collect::<Vec<i64>>(), (0..290).collect::<Vec<i64>>());
 
-        assert_eq!(senders.get(&3).unwrap().len(), 2);
-        assert_eq!(senders.get(&5).unwrap().get(0).unwrap().type_, 5);
-        assert_eq!(senders.get(&6).unwrap().get(0).unwrap().id, 10);
-    
-        assert_eq!(tx_count, 31); // 2*9 (single digits) + 12 (tens) + 1 (hundreds) = 31
+        // round trips of a mid sized batch
+        let data: Vec<MockData> = (0..99).map(|i| MockData { id: i, modified_date: Utc::now().naive_utc() }).collect();
+        assert_eq!(split_vec(data.clone()).len(), 10);
+        assert_eq!(split_vec_with_fifties(data).len(), 6);
+
+        let data: Vec<MockData> = (0..49).map(|i| MockData { id: i, modified_date: Utc::now().naive_utc() }).collect();
+        assert_eq!(split_vec_with_fifties(data.clone()).len(), split_vec(data).len());
     }
 
     #[test]
@@ -634,6 +4090,21 @@ mod tests {
         assert_eq!(result[6][9].id, 249);
     }
 
+    #[test]
+    fn test_observe_batch_duration() {
+        let mut builder = builder::tests::test_builder();
+        builder.slow_batch_threshold(Duration::from_millis(500));
+        let processor = builder.build_update();
+
+        processor.observe_batch_duration(1, 0, 1, Duration::from_millis(100));
+        assert_eq!(processor.slow_batch_count(), 0);
+
+        processor.observe_batch_duration(1, 0, 1, Duration::from_millis(600));
+        let clone = processor.clone();
+        clone.observe_batch_duration(100, 1, 100, Duration::from_secs(1));
+        assert_eq!(processor.slow_batch_count(), 2);
+    }
+
     #[test]
     fn test_split_vec_edge_cases() {
         let empty_data: Vec<MockData> = vec![];
@@ -644,5 +4115,22 @@ mod tests {
         let result = split_vec(single_data.clone());
         assert_eq!(result.len(), 1);
         assert_eq!(result[0], single_data);
+
+        let hundred_data: Vec<MockData> = (0..100).map(|i| MockData { id: i, modified_date: Utc::now().naive_utc() }).collect();
+        let result = split_vec(hundred_data);
+        assert_eq!(result.len(), 1);
+        assert!(result.iter().all(|batch| !batch.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_push_to_handle_skips_empty_batches() {
+        let builder = builder::tests::test_builder();
+        let processor = builder.build_update();
+
+        let mut senders = HashMap::new();
+        let mut tx_count = 0;
+        processor.push_to_handle::<MockData>(&mut senders, vec![vec![]], &mut tx_count).await.unwrap();
+
+        assert_eq!(tx_count, 0);
     }
 }
\ No newline at end of file